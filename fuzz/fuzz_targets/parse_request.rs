@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Run with `cargo +nightly fuzz run parse_request` from the repository root.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = jobctl::protocol::parse_request(data) {
+        // Anything we accept must survive a round trip unchanged.
+        let encoded = serde_json::to_vec(&request).unwrap();
+        jobctl::protocol::parse_request(&encoded).unwrap();
+    }
+});
@@ -1,182 +1,55 @@
 use clap::Parser;
-use jobctl::utils::time_ago;
-
-use std::path::PathBuf;
-use std::process::Command as ProcessCommand;
-use std::process::exit;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{
-    fs,
-    io::{BufRead, BufReader, Write},
-    os::unix::net::{UnixListener, UnixStream},
-    thread,
+use jobctl::daemon::{
+    EXIT_ALREADY_RUNNING, daemonize, exec_upgrade, inherited_listener, listen_fds_listener,
+    lock_pidfile, log_dir, pidfile_path, remove_pidfile, running_pid, write_pidfile,
 };
+use jobctl::http::{check_address, spawn_http, token_path, write_token};
+#[cfg(not(feature = "async"))]
+use jobctl::server::serve;
+#[cfg(feature = "async")]
+use jobctl::server::serve_async;
+use jobctl::server::{
+    Server, Shutdown, block_shutdown_signals, spawn_idle_watchdog, spawn_policy_enforcer,
+    spawn_reaper, spawn_signal_handler,
+};
+use jobctl::sessions::{load_sessions, sessions_file};
+use jobctl::utils::{child_processes, socket_path};
+
+use std::fs::File;
+use std::io::Write;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::{fs, os::unix::net::UnixListener};
 use tracing::{error, info};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+
+/// Tell the process that spawned us that the socket is accepting connections.
+/// Fails if it has stopped waiting and closed its end.
+fn notify_ready(fd: RawFd) -> std::io::Result<()> {
+    let mut ready = unsafe { File::from_raw_fd(fd) };
+    ready.write_all(b"\n")
+}
 
-use jobctl::cli::Commands;
-use jobctl::sessions::{ClientRequest, Job, JobOutput, ServerResponse, Session, cleanup_sessions};
-
-fn handle_client(mut stream: UnixStream, store: &Arc<Mutex<Vec<Session>>>) -> std::io::Result<()> {
-    let mut kill_after_response = false;
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut line = String::new();
-
-    reader.read_line(&mut line)?;
-
-    let req: ClientRequest = match serde_json::from_str(&line) {
-        Ok(r) => {
-            info!("{}", serde_json::to_string_pretty(&r).unwrap());
-            r
-        }
-        Err(e) => {
-            let err = ServerResponse::Error {
-                message: format!("Request Error: {}", e),
-            };
-            let payload = serde_json::to_string(&err).unwrap();
-            writeln!(stream, "{}", payload)?;
-            return Ok(());
-        }
-    };
-
-    info!("Processing action: {:?}", req.action);
-
-    let response = match req.action {
-        Commands::List { dir, fzf: _ } => {
-            let sessions = cleanup_sessions(store);
-
-            match dir {
-                Some(directory) => {
-                    let directory = PathBuf::from(directory);
-                    let session = sessions
-                        .iter()
-                        .find(|s| s.directory == directory)
-                        .expect("No jobs found for directory");
-                    let jobs = session
-                        .jobs
-                        .clone()
-                        .iter()
-                        .map(|job| JobOutput {
-                            pid: job.pid,
-                            command: job.command.clone(),
-                            number: job.number,
-                            suspended: time_ago(job.suspended),
-                        })
-                        .collect();
-                    ServerResponse::ListJobs { jobs }
-                }
-                _ => ServerResponse::ListSessions { sessions },
-            }
-        }
-        Commands::Register {
-            pid,
-            number,
-            command,
-        } => {
-            let job = Job {
-                pid,
-                number,
-                command,
-                suspended: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or(Duration::new(0, 0))
-                    .as_secs(),
-            };
-
-            info!("Creating new job: {:?}", job);
-
-            let mut sessions = store.lock().unwrap();
-            if let Some(session) = sessions.iter_mut().find(|s| s.directory == req.cwd) {
-                if session.jobs.iter().all(|j| j.pid != job.pid) {
-                    session.jobs.push(job.clone());
-                    info!("Adding to job to session: {:?}", session);
-                } else {
-                    info!(
-                        "Job with PID {} already exists in session: {:?}",
-                        job.pid, session
-                    );
-                }
-            } else {
-                let session = Session {
-                    jobs: vec![job.clone()],
-                    directory: req.cwd,
-                };
-
-                info!("No session found, creating session: {:?}", session);
-
-                sessions.push(session);
+fn bind_socket(socket_path: &Path) -> UnixListener {
+    if let Err(e) = fs::remove_file(socket_path) {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => {}
+            _ => {
+                error!("IO Error: Failed to remove `{}`", socket_path.display());
+                error!("IO Error: {}", e);
             }
-
-            ServerResponse::Register { job }
         }
-        Commands::Kill => {
-            kill_after_response = true;
-            ServerResponse::Kill
-        }
-        Commands::Run { command } => {
-            // Spawn the command as a background process
-            let child = match ProcessCommand::new("sh").arg("-c").arg(&command).spawn() {
-                Ok(child) => child,
-                Err(e) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Failed to spawn process: {}", e),
-                    ));
-                }
-            };
-
-            let pid = child.id();
-
-            let job = Job {
-                pid,
-                number: 0,
-                command: command.clone(),
-                suspended: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or(Duration::new(0, 0))
-                    .as_secs(),
-            };
-
-            info!("Spawning new job: {:?}", job);
-
-            let mut sessions = store.lock().unwrap();
-            if let Some(session) = sessions.iter_mut().find(|s| s.directory == req.cwd) {
-                if session.jobs.iter().all(|j| j.pid != job.pid) {
-                    session.jobs.push(job.clone());
-                    info!("Adding spawned job to session: {:?}", session);
-                } else {
-                    info!(
-                        "Job with PID {} already exists in session: {:?}",
-                        job.pid, session
-                    );
-                }
-            } else {
-                let session = Session {
-                    jobs: vec![job.clone()],
-                    directory: req.cwd,
-                };
-
-                info!(
-                    "No session found, creating session for spawned job: {:?}",
-                    session
-                );
-
-                sessions.push(session);
-            }
-
-            ServerResponse::Register { job }
+    }
+    match UnixListener::bind(socket_path) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to bind socket (another instance running?): {}", e);
+            std::process::exit(1);
         }
-        _ => todo!(),
-    };
-
-    let payload = serde_json::to_string(&response).unwrap();
-    writeln!(stream, "{}", payload)?;
-
-    if kill_after_response {
-        exit(0);
     }
-
-    Ok(())
 }
 
 fn main() -> std::io::Result<()> {
@@ -189,46 +62,175 @@ fn main() -> std::io::Result<()> {
         _ => tracing::Level::TRACE,
     };
 
-    tracing_subscriber::fmt().with_max_level(level).init();
+    // systemd socket activation: the service manager owns the socket and
+    // supervises us, so there is nothing to bind and no reason to detach.
+    let activated = listen_fds_listener()?;
+    let daemon = args.daemon && !args.foreground && activated.is_none();
+    // After an upgrade exec we are already detached; only the logging applies.
+    let detach = daemon && args.listen_fd.is_none();
+    let pidfile = pidfile_path()?;
+
+    if daemon {
+        // Until stdio is detached this also reaches whoever started us.
+        let appender = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("job-server")
+            .filename_suffix("log")
+            .max_log_files(7)
+            .build(log_dir()?)
+            .map_err(std::io::Error::other)?;
+
+        tracing_subscriber::fmt()
+            .with_max_level(level)
+            .with_ansi(false)
+            .with_writer(std::io::stderr.and(appender))
+            .init();
+    } else {
+        tracing_subscriber::fmt().with_max_level(level).init();
+    }
 
-    let uid = unsafe { libc::getuid() };
-    let socket_path = format!("/tmp/jobctl-{}.sock", uid);
-    if let Err(e) = fs::remove_file(PathBuf::from(&socket_path)) {
-        match e.kind() {
-            std::io::ErrorKind::NotFound => {}
-            _ => {
-                error!("IO Error: Failed to remove `{}`", &socket_path);
-                error!("IO Error: {}", e);
-            }
+    // Held before the socket is touched, so a second server started at the
+    // same time cannot unlink the first one's socket.
+    let Some(lock) = lock_pidfile(&pidfile)? else {
+        match running_pid(&pidfile) {
+            Some(pid) => error!("job-server is already running with pid {}", pid),
+            None => error!("job-server is already running"),
         }
-    }
-    let listener = match UnixListener::bind(&socket_path) {
-        Ok(l) => l,
-        Err(e) => {
-            error!("Failed to bind socket (another instance running?): {}", e);
-            std::process::exit(1);
+        std::process::exit(EXIT_ALREADY_RUNNING);
+    };
+
+    let socket_path = socket_path();
+    let owns_socket = activated.is_none() && !args.external_socket;
+    let listener = match (activated, args.listen_fd) {
+        (Some(listener), _) => {
+            info!("Using listening socket passed via LISTEN_FDS");
+            listener
+        }
+        (None, Some(fd)) => {
+            info!("Using listening socket handed over on fd {}", fd);
+            inherited_listener(fd)?
         }
+        (None, None) => bind_socket(&socket_path),
     };
-    let store: Arc<Mutex<Vec<Session>>> = Arc::new(Mutex::new(vec![]));
-
-    info!("Server Started:  {}", socket_path);
-
-    for incoming in listener.incoming() {
-        match incoming {
-            Ok(stream) => {
-                let store = Arc::clone(&store);
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, &store) {
-                        error!("Client Error: {}", e);
-                    }
-                });
+    let handover = listener.try_clone()?;
+    let http = match args.http {
+        Some(addr) => match TcpListener::bind(addr) {
+            Ok(listener) => {
+                check_address(&listener);
+                Some(listener)
             }
             Err(e) => {
-                error!("Socket Listener Error: {}", e);
-                break;
+                error!("Failed to bind HTTP listener on {}: {}", addr, e);
+                std::process::exit(1);
             }
+        },
+        None => None,
+    };
+    let state_file = sessions_file()?;
+    let server = Arc::new(Server::new(
+        load_sessions(&state_file),
+        socket_path.clone(),
+    )?);
+
+    if detach {
+        daemonize()?;
+    }
+
+    write_pidfile(&lock)?;
+
+    block_shutdown_signals()?;
+    spawn_signal_handler(Arc::clone(&server))?;
+
+    if let Some(timeout) = args.idle_timeout {
+        spawn_idle_watchdog(Arc::clone(&server), timeout)?;
+    }
+    spawn_policy_enforcer(Arc::clone(&server))?;
+
+    // Jobs started with `run` before an upgrade are still our children.
+    if args.listen_fd.is_some() {
+        let children = child_processes();
+        if !children.is_empty() {
+            info!("Adopted {} job(s) from the previous server", children.len());
+            spawn_reaper(Arc::clone(&server), children)?;
+        }
+    }
+
+    if let Some(listener) = http {
+        let token = write_token()?;
+        info!(
+            "Dashboard:       http://{}/ (token in {})",
+            listener.local_addr()?,
+            token_path()?.display()
+        );
+        spawn_http(listener, Arc::clone(&server), token)?;
+    }
+
+    info!("Server Started:  {}", socket_path.display());
+
+    if let Some(fd) = args.ready_fd
+        && let Err(e) = notify_ready(fd)
+    {
+        // The client timed out and reported that the server failed to
+        // start, so do not linger as a server nobody knows about.
+        error!("Failed to signal readiness on fd {}: {}", fd, e);
+        if owns_socket && let Err(e) = fs::remove_file(&socket_path) {
+            error!("Failed to remove `{}`: {}", socket_path.display(), e);
+        }
+        remove_pidfile(&pidfile);
+        std::process::exit(1);
+    }
+
+    #[cfg(feature = "async")]
+    let reason = {
+        listener.set_nonblocking(true)?;
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_io()
+            .enable_time()
+            .build()?;
+
+        let server = Arc::clone(&server);
+        runtime.block_on(async move {
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            serve_async(listener, server).await
+        })?
+    };
+
+    #[cfg(not(feature = "async"))]
+    let reason = serve(listener, Arc::clone(&server))?;
+
+    info!("Server stopping: {:?}", reason);
+
+    if let Err(e) = server.finish(&reason, &state_file) {
+        error!("Failed to save state: {}", e);
+    }
+
+    if let Shutdown::Upgrade(binary) = &reason {
+        let mut exec_args = vec![];
+        if args.verbose > 0 {
+            exec_args.push(format!("-{}", "v".repeat(args.verbose as usize)));
+        }
+        if daemon {
+            exec_args.push("--daemon".to_string());
+        }
+        if let Some(timeout) = args.idle_timeout {
+            exec_args.push(format!("--idle-timeout={}s", timeout.as_secs()));
         }
+        if let Some(addr) = args.http {
+            exec_args.push(format!("--http={}", addr));
+        }
+        if !owns_socket {
+            exec_args.push("--external-socket".to_string());
+        }
+
+        info!("Handing over to {}", binary.display());
+        let e = exec_upgrade(binary, &handover, &exec_args);
+        error!("Failed to exec {}: {}", binary.display(), e);
+    }
+    if owns_socket && let Err(e) = fs::remove_file(&socket_path) {
+        error!("Failed to remove `{}`: {}", socket_path.display(), e);
     }
+    remove_pidfile(&pidfile);
 
     Ok(())
 }
@@ -1,5 +1,12 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::sessions::ResourceUsage;
+use crate::utils::{parse_duration, parse_ionice, parse_signal, parse_size};
 
 pub const ZSH: &str = include_str!("../resources/hooks.zsh");
 
@@ -9,18 +16,28 @@ pub struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
 
+    /// How long to wait for an auto-started server to become ready
+    #[arg(long, global = true, default_value = "5s", value_parser = parse_duration)]
+    pub start_timeout: Duration,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
-#[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[derive(Subcommand, Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(tag = "action", rename_all = "snake_case")]
 pub enum Commands {
     List {
         #[arg(long)]
+        #[serde(default)]
         fzf: bool,
         #[arg()]
         dir: Option<String>,
+        /// Order jobs, or sessions by their totals, by resource usage,
+        /// largest first
+        #[arg(long, value_enum)]
+        #[serde(skip)]
+        sort: Option<SortKey>,
     },
     Register {
         #[arg(short, long)]
@@ -30,14 +47,336 @@ pub enum Commands {
         #[arg(skip)]
         command: String,
     },
+    /// Start a command in the background and track it as a job
     Run {
         #[arg()]
         command: String,
+        #[command(flatten)]
+        #[serde(flatten)]
+        options: RunOptions,
+    },
+    Kill {
+        /// What to do with tracked jobs before the server exits
+        #[arg(long, value_enum, default_value_t = JobsPolicy::Keep)]
+        #[serde(default)]
+        jobs: JobsPolicy,
+    },
+    /// Apply the stale job policies now
+    Gc {
+        /// Only list the jobs the policies apply to
+        #[arg(long)]
+        #[serde(default)]
+        dry_run: bool,
+        /// Print the jobs as JSON
+        #[arg(long)]
+        #[serde(skip)]
+        json: bool,
+    },
+    /// Send a signal to a job's process group
+    Signal {
+        /// `%N`, a job number or PID, `pid:N`, or part of the command
+        job: String,
+        /// Signal name or number, e.g. `TERM`, `SIGUSR1` or `9`; `0` only
+        /// checks that the job is still alive
+        #[arg(value_parser = parse_signal)]
+        signal: i32,
+    },
+    /// Page out the memory of a suspended job to swap
+    ///
+    /// job-server needs CAP_SYS_NICE for this, which can be granted with
+    /// `setcap cap_sys_nice+ep` on its binary.
+    Freeze {
+        /// `%N`, a job number or PID, `pid:N`, or part of the command
+        job: String,
+    },
+    /// Show what jobctl knows about a job
+    Inspect {
+        /// `%N`, a job number or PID, `pid:N`, or part of the command
+        job: String,
+        /// Also show the job's process tree
+        #[arg(long)]
+        #[serde(default)]
+        tree: bool,
+        /// Print the inspection as JSON
+        #[arg(long)]
+        #[serde(skip)]
+        json: bool,
+    },
+    /// Find the tracked job, or other process, holding a port
+    WhichPort {
+        port: u16,
+        /// Print the owners as JSON
+        #[arg(long)]
+        #[serde(skip)]
+        json: bool,
+    },
+    /// Change the niceness of every process in a job
+    Renice {
+        /// `%N`, a job number or PID, `pid:N`, or part of the command
+        job: String,
+        /// From -20 (highest priority) to 19 (lowest)
+        #[arg(allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+        #[schemars(range(min = -20, max = 19))]
+        nice: i32,
+    },
+    /// Stop a job with SIGSTOP
+    #[serde(skip)]
+    Stop {
+        job: String,
+    },
+    /// Continue a stopped job in the background with SIGCONT
+    #[serde(skip)]
+    Cont {
+        job: String,
+    },
+    /// Ask a job to exit with SIGTERM
+    #[serde(skip)]
+    Term {
+        job: String,
+    },
+    /// Continue a stopped job, or print the `fg` command for it
+    #[serde(skip)]
+    Resume {
+        /// `%N`, a job number or PID, `pid:N`, or part of the command
+        job: String,
+        /// Send SIGCONT so the job keeps running in the background, from
+        /// any terminal
+        #[arg(long)]
+        background: bool,
     },
-    Kill,
     Init {
         shell: String,
     },
+    /// Act on every job in a directory at once
+    #[serde(skip)]
+    Session {
+        #[command(subcommand)]
+        command: SessionCommands,
+    },
+    /// Terminate every job in `dir`, then forget the session
+    #[command(skip)]
+    KillSession {
+        dir: PathBuf,
+        /// Milliseconds to wait after SIGTERM before sending SIGKILL
+        #[serde(default = "default_grace_ms")]
+        grace_ms: u64,
+    },
+    /// Manage the job-server daemon itself
+    #[serde(skip)]
+    Server {
+        #[command(subcommand)]
+        command: ServerCommands,
+    },
+    /// Report the server's version, uptime and load
+    #[command(skip)]
+    Status,
+    /// Re-exec the server as `binary`, handing over its state and socket
+    #[command(skip)]
+    Upgrade {
+        binary: PathBuf,
+    },
+    /// Print JSON Schemas for the socket protocol types
+    #[serde(skip)]
+    Schema {
+        /// Only print the schema for this type, e.g. `ClientRequest`
+        name: Option<String>,
+    },
+    /// Report Prometheus metrics in the text exposition format
+    #[command(skip)]
+    Metrics,
+    /// Write systemd user units that start job-server on demand
+    InstallService {
+        /// Overwrite existing unit files
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+impl Commands {
+    /// The `action` this command is sent as on the socket.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Commands::List { .. } => "list",
+            Commands::Register { .. } => "register",
+            Commands::Run { .. } => "run",
+            Commands::Kill { .. } => "kill",
+            Commands::Gc { .. } => "gc",
+            Commands::Signal { .. } => "signal",
+            Commands::Freeze { .. } => "freeze",
+            Commands::Renice { .. } => "renice",
+            Commands::Inspect { .. } => "inspect",
+            Commands::WhichPort { .. } => "which_port",
+            Commands::Stop { .. } => "stop",
+            Commands::Cont { .. } => "cont",
+            Commands::Term { .. } => "term",
+            Commands::Resume { .. } => "resume",
+            Commands::Init { .. } => "init",
+            Commands::Session { .. } => "session",
+            Commands::KillSession { .. } => "kill_session",
+            Commands::Server { .. } => "server",
+            Commands::Status => "status",
+            Commands::Upgrade { .. } => "upgrade",
+            Commands::Schema { .. } => "schema",
+            Commands::Metrics => "metrics",
+            Commands::InstallService { .. } => "install_service",
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionCommands {
+    /// Terminate every job in a directory and remove its session
+    Kill {
+        /// Directory whose jobs to kill (defaults to the current one)
+        dir: Option<PathBuf>,
+        /// How long jobs get to exit after SIGTERM before SIGKILL (at most 20s)
+        #[arg(long, default_value = "5s", value_parser = parse_duration)]
+        grace: Duration,
+        /// Print the outcome of each job as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn default_grace_ms() -> u64 {
+    5000
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ServerCommands {
+    /// Show the server's version, uptime, PID, socket and load
+    Status {
+        /// Print the status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Replace the running server with a new build without losing jobs
+    Upgrade {
+        /// job-server binary to switch to (defaults to the one next to jobctl)
+        #[arg(long)]
+        binary: Option<PathBuf>,
+    },
+    /// Print the server's Prometheus metrics
+    Metrics,
+}
+
+/// Scheduling priority and resource limits for a job started with `run`,
+/// applied before the command is executed.
+#[derive(Args, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct RunOptions {
+    /// Niceness to start at, from -20 (highest priority) to 19 (lowest)
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = -20, max = 19))]
+    pub nice: Option<i32>,
+    /// I/O scheduling class: `idle`, `best-effort` or `realtime`, with an
+    /// optional level from 0 (highest) to 7, e.g. `best-effort:7`
+    #[arg(long, value_parser = parse_ionice)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ionice: Option<IoPriority>,
+    /// Address space limit, e.g. `512M` or `2G`
+    #[arg(long, value_parser = parse_size)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_mem: Option<u64>,
+    /// CPU time limit in seconds, after which the job gets SIGXCPU
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_cpu_seconds: Option<u64>,
+    /// Limit on open file descriptors
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<u64>,
+}
+
+impl RunOptions {
+    /// Check values a protocol client could send that the CLI rejects.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(nice) = self.nice
+            && !(-20..=19).contains(&nice)
+        {
+            return Err(format!("nice must be between -20 and 19, not {}", nice));
+        }
+        if let Some(ionice) = self.ionice
+            && ionice.level > 7
+        {
+            return Err(format!(
+                "ionice level must be between 0 and 7, not {}",
+                ionice.level
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct IoPriority {
+    pub class: IoClass,
+    /// 0 (highest) to 7; ignored for `idle`.
+    #[serde(default)]
+    #[schemars(range(max = 7))]
+    pub level: u8,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl IoPriority {
+    /// The value `ioprio_set(2)` takes.
+    pub fn value(self) -> i32 {
+        let class = match self.class {
+            IoClass::Realtime => 1,
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        };
+        (class << 13) | self.level as i32
+    }
+}
+
+/// Resource to order `list` output by.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Resident memory
+    Mem,
+    /// Virtual memory
+    Vmem,
+    /// CPU time used so far
+    Cpu,
+    Threads,
+    /// Number of descendant processes
+    Children,
+}
+
+impl SortKey {
+    pub fn value(self, usage: &ResourceUsage) -> u64 {
+        match self {
+            SortKey::Mem => usage.rss,
+            SortKey::Vmem => usage.virtual_memory,
+            SortKey::Cpu => usage.cpu_time_ms,
+            SortKey::Threads => usage.threads,
+            SortKey::Children => usage.children,
+        }
+    }
+}
+
+#[derive(
+    ValueEnum, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum JobsPolicy {
+    /// Leave jobs running or stopped as they are
+    #[default]
+    Keep,
+    /// Send SIGCONT and SIGTERM to every job's process group
+    Term,
+    /// Send SIGKILL to every job's process group
+    Kill,
 }
 
 #[derive(Parser)]
@@ -45,4 +384,33 @@ pub enum Commands {
 pub struct ServerArgs {
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
+
+    /// Detach from the terminal, write a pidfile and log to the state directory
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Stay attached to the terminal and log to stderr (overrides --daemon)
+    #[arg(long, overrides_with = "daemon")]
+    pub foreground: bool,
+
+    /// Exit after this long with no jobs and no client connections, e.g. `30m`
+    #[arg(long, env = "JOBCTL_IDLE_TIMEOUT", value_parser = parse_duration)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Also serve the HTTP API and dashboard here, e.g. `127.0.0.1:8080`.
+    /// Clients must send the token written to `http-token` in the state dir
+    #[arg(long, value_name = "ADDR")]
+    pub http: Option<SocketAddr>,
+
+    /// Write a byte to this inherited file descriptor once the socket is bound
+    #[arg(long, hide = true)]
+    pub ready_fd: Option<i32>,
+
+    /// Serve on this inherited listening socket, as handed over by an upgrade
+    #[arg(long, hide = true)]
+    pub listen_fd: Option<i32>,
+
+    /// The listening socket belongs to the service manager; leave it in place
+    #[arg(long, hide = true)]
+    pub external_socket: bool,
 }
@@ -0,0 +1,751 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use crate::ClientError;
+use crate::cli::{Commands, JobsPolicy, RunOptions};
+use crate::daemon::EXIT_ALREADY_RUNNING;
+use crate::protocol::{CLIENT_IO_TIMEOUT, read_message, write_message};
+#[cfg(feature = "async")]
+use crate::protocol::{read_message_async, write_message_async};
+use crate::sessions::{
+    ClientRequest, Inspection, Job, JobOutput, KilledJob, PortOwner, ServerResponse, ServerStatus,
+    Session, StaleJob,
+};
+use crate::utils::socket_path;
+
+/// Default time to wait for an auto-started server to become ready.
+pub const DEFAULT_START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Typed client for the `job-server` unix socket protocol.
+///
+/// Every request is sent on behalf of a working directory, which the server
+/// uses to pick the session a job belongs to.
+#[derive(Debug, Clone)]
+pub struct JobctlClient {
+    cwd: PathBuf,
+    socket_path: PathBuf,
+    start_timeout: Duration,
+}
+
+impl JobctlClient {
+    /// Create a client that talks to the default socket for the current user.
+    pub fn new(cwd: impl Into<PathBuf>) -> Self {
+        Self {
+            cwd: cwd.into(),
+            socket_path: socket_path(),
+            start_timeout: DEFAULT_START_TIMEOUT,
+        }
+    }
+
+    /// Use a different socket path than the per-user default.
+    ///
+    /// `job-server` only binds the default path itself, so no server is
+    /// auto-started for a different one; it has to be provided some other
+    /// way, such as socket activation.
+    pub fn with_socket_path(mut self, socket_path: impl Into<PathBuf>) -> Self {
+        self.socket_path = socket_path.into();
+        self
+    }
+
+    /// How long to wait for an auto-started server to report it is ready.
+    pub fn with_start_timeout(mut self, timeout: Duration) -> Self {
+        self.start_timeout = timeout;
+        self
+    }
+
+    /// Working directory sent along with every request.
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    /// Socket path this client connects to.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Send an action and return the raw server response.
+    ///
+    /// When `should_start` is set the server is spawned if it is not running.
+    /// `ServerResponse::Error` is turned into the `ClientError` matching its code.
+    pub fn request(
+        &self,
+        action: Commands,
+        should_start: bool,
+    ) -> Result<ServerResponse, ClientError> {
+        let request = ClientRequest {
+            action,
+            cwd: self.cwd.clone(),
+        };
+
+        into_result(send_request_to(
+            &self.socket_path,
+            request,
+            Some(should_start),
+            self.start_timeout,
+        )?)
+    }
+
+    /// List every directory that currently has suspended jobs.
+    pub fn list_sessions(&self) -> Result<Vec<Session>, ClientError> {
+        into_sessions(self.request(list_action(None), false)?)
+    }
+
+    /// List the jobs belonging to the session for `dir`.
+    pub fn list_jobs(&self, dir: impl AsRef<Path>) -> Result<Vec<JobOutput>, ClientError> {
+        into_jobs(self.request(list_action(Some(dir.as_ref())), false)?)
+    }
+
+    /// Record a suspended shell job, starting the server if needed.
+    pub fn register(&self, pid: u32, number: u8, command: &str) -> Result<Job, ClientError> {
+        into_job(self.request(register_action(pid, number, command), true)?)
+    }
+
+    /// Have the server spawn `command` in the background, starting it if needed.
+    pub fn run(&self, command: &str) -> Result<Job, ClientError> {
+        self.run_with(command, RunOptions::default())
+    }
+
+    /// Like [`JobctlClient::run`], with a starting priority and resource
+    /// limits for the job.
+    pub fn run_with(&self, command: &str, options: RunOptions) -> Result<Job, ClientError> {
+        into_job(self.request(run_action(command, options), true)?)
+    }
+
+    /// Ask the server to shut down, applying `jobs` to the tracked jobs first.
+    pub fn kill(&self, jobs: JobsPolicy) -> Result<(), ClientError> {
+        into_kill(self.request(Commands::Kill { jobs }, false)?)
+    }
+
+    /// Send `signal` to the job in the current directory matching `job`.
+    /// Does not start the server.
+    pub fn signal(&self, job: &str, signal: i32) -> Result<Job, ClientError> {
+        into_signalled(self.request(signal_action(job, signal), false)?)
+    }
+
+    /// Page out the memory of the suspended job in the current directory
+    /// matching `job`, returning it and the bytes reclaimed. Does not start
+    /// the server.
+    pub fn freeze(&self, job: &str) -> Result<(Job, u64), ClientError> {
+        into_frozen(self.request(freeze_action(job), false)?)
+    }
+
+    /// Describe the job in the current directory matching `job`, with its
+    /// process tree if `tree` is set. Does not start the server.
+    pub fn inspect(&self, job: &str, tree: bool) -> Result<Inspection, ClientError> {
+        into_inspection(self.request(inspect_action(job, tree), false)?)
+    }
+
+    /// Find the processes listening on or bound to `port`, and the tracked
+    /// jobs they belong to. Does not start the server.
+    pub fn which_port(&self, port: u16) -> Result<Vec<PortOwner>, ClientError> {
+        into_owners(self.request(which_port_action(port), false)?)
+    }
+
+    /// Set the niceness of every process in the job in the current
+    /// directory matching `job`. Returns the job and how many threads were
+    /// changed. Does not start the server.
+    pub fn renice(&self, job: &str, nice: i32) -> Result<(Job, u32), ClientError> {
+        into_reniced(self.request(renice_action(job, nice), false)?)
+    }
+
+    /// Terminate every job in `dir` and remove its session, giving jobs
+    /// `grace` to exit after SIGTERM. Does not start the server.
+    pub fn kill_session(
+        &self,
+        dir: impl AsRef<Path>,
+        grace: Duration,
+    ) -> Result<Vec<KilledJob>, ClientError> {
+        into_killed(self.request(kill_session_action(dir.as_ref(), grace), false)?)
+    }
+
+    /// Apply the stale job policies now, or with `dry_run` list the jobs
+    /// they apply to. Does not start the server.
+    pub fn gc(&self, dry_run: bool) -> Result<Vec<StaleJob>, ClientError> {
+        into_stale(self.request(gc_action(dry_run), false)?)
+    }
+
+    /// Ask the server about itself.
+    pub fn status(&self) -> Result<ServerStatus, ClientError> {
+        into_status(self.request(Commands::Status, false)?)
+    }
+
+    /// Prometheus metrics in the text exposition format. Does not start
+    /// the server.
+    pub fn metrics(&self) -> Result<String, ClientError> {
+        into_metrics(self.request(Commands::Metrics, false)?)
+    }
+
+    /// Ask the server to re-exec itself as `binary`, keeping its state and
+    /// socket. Returns the server's PID, which does not change.
+    pub fn upgrade(&self, binary: &Path) -> Result<u32, ClientError> {
+        let action = Commands::Upgrade {
+            binary: binary.to_path_buf(),
+        };
+
+        into_upgrade(self.request(action, false)?)
+    }
+}
+
+fn list_action(dir: Option<&Path>) -> Commands {
+    Commands::List {
+        fzf: false,
+        dir: dir.map(|dir| dir.to_string_lossy().into()),
+        sort: None,
+    }
+}
+
+fn register_action(pid: u32, number: u8, command: &str) -> Commands {
+    Commands::Register {
+        pid,
+        number,
+        command: command.to_string(),
+    }
+}
+
+fn run_action(command: &str, options: RunOptions) -> Commands {
+    Commands::Run {
+        command: command.to_string(),
+        options,
+    }
+}
+
+fn signal_action(job: &str, signal: i32) -> Commands {
+    Commands::Signal {
+        job: job.to_string(),
+        signal,
+    }
+}
+
+fn kill_session_action(dir: &Path, grace: Duration) -> Commands {
+    Commands::KillSession {
+        dir: dir.to_path_buf(),
+        grace_ms: grace.as_millis() as u64,
+    }
+}
+
+fn freeze_action(job: &str) -> Commands {
+    Commands::Freeze {
+        job: job.to_string(),
+    }
+}
+
+fn inspect_action(job: &str, tree: bool) -> Commands {
+    Commands::Inspect {
+        job: job.to_string(),
+        tree,
+        json: false,
+    }
+}
+
+fn which_port_action(port: u16) -> Commands {
+    Commands::WhichPort { port, json: false }
+}
+
+fn renice_action(job: &str, nice: i32) -> Commands {
+    Commands::Renice {
+        job: job.to_string(),
+        nice,
+    }
+}
+
+fn gc_action(dry_run: bool) -> Commands {
+    Commands::Gc {
+        dry_run,
+        json: false,
+    }
+}
+
+fn into_result(response: ServerResponse) -> Result<ServerResponse, ClientError> {
+    match response {
+        ServerResponse::Error { code, message } => Err(ClientError::from_server(code, message)),
+        response => Ok(response),
+    }
+}
+
+fn into_sessions(response: ServerResponse) -> Result<Vec<Session>, ClientError> {
+    match response {
+        ServerResponse::ListSessions { sessions } => Ok(sessions),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_jobs(response: ServerResponse) -> Result<Vec<JobOutput>, ClientError> {
+    match response {
+        ServerResponse::ListJobs { jobs } => Ok(jobs),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_job(response: ServerResponse) -> Result<Job, ClientError> {
+    match response {
+        ServerResponse::Register { job } => Ok(job),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_kill(response: ServerResponse) -> Result<(), ClientError> {
+    match response {
+        ServerResponse::Kill => Ok(()),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_status(response: ServerResponse) -> Result<ServerStatus, ClientError> {
+    match response {
+        ServerResponse::Status { status } => Ok(status),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_signalled(response: ServerResponse) -> Result<Job, ClientError> {
+    match response {
+        ServerResponse::Signal { job, .. } => Ok(job),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_frozen(response: ServerResponse) -> Result<(Job, u64), ClientError> {
+    match response {
+        ServerResponse::Freeze { job, reclaimed } => Ok((job, reclaimed)),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_inspection(response: ServerResponse) -> Result<Inspection, ClientError> {
+    match response {
+        ServerResponse::Inspect { inspection } => Ok(*inspection),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_owners(response: ServerResponse) -> Result<Vec<PortOwner>, ClientError> {
+    match response {
+        ServerResponse::WhichPort { owners, .. } => Ok(owners),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_reniced(response: ServerResponse) -> Result<(Job, u32), ClientError> {
+    match response {
+        ServerResponse::Renice { job, threads, .. } => Ok((job, threads)),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_killed(response: ServerResponse) -> Result<Vec<KilledJob>, ClientError> {
+    match response {
+        ServerResponse::KillSession { jobs, .. } => Ok(jobs),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_stale(response: ServerResponse) -> Result<Vec<StaleJob>, ClientError> {
+    match response {
+        ServerResponse::Gc { jobs } => Ok(jobs),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_metrics(response: ServerResponse) -> Result<String, ClientError> {
+    match response {
+        ServerResponse::Metrics { text } => Ok(text),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn into_upgrade(response: ServerResponse) -> Result<u32, ClientError> {
+    match response {
+        ServerResponse::Upgrade { pid } => Ok(pid),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn unexpected(response: &ServerResponse) -> ClientError {
+    ClientError::InvalidResponse(format!("unexpected response: {:?}", response))
+}
+
+/// Spawn `job-server` and block until it reports that its socket is bound.
+///
+/// The server is handed the write end of a pipe via `--ready-fd` and writes a
+/// byte to it once it is accepting connections. If the pipe closes first, or
+/// `timeout` passes, the server's stderr is returned in the error.
+pub fn start_server(timeout: Duration) -> Result<(), ClientError> {
+    let started = Instant::now();
+    let exe = env::current_exe()?;
+    let server_path = exe.with_file_name("job-server");
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let ready = unsafe { File::from_raw_fd(fds[0]) };
+    let ready_fd = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+    let raw_ready_fd = ready_fd.as_raw_fd();
+
+    // stderr goes to an unlinked file rather than a pipe so the server and
+    // the jobs it spawns never hit EPIPE once this process has exited.
+    let mut stderr = stderr_file()?;
+
+    let mut command = Command::new(server_path);
+    command
+        .arg("--daemon")
+        .arg("--ready-fd")
+        .arg(raw_ready_fd.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(stderr.try_clone()?);
+
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(raw_ready_fd, libc::F_SETFD, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    drop(ready_fd);
+
+    let mut pollfd = libc::pollfd {
+        fd: ready.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let polled = loop {
+        let rc = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if rc == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        break rc;
+    };
+
+    let mut byte = [0u8; 1];
+    let ready_byte = polled > 0 && (&ready).read(&mut byte).unwrap_or(0) == 1;
+
+    if ready_byte {
+        // With --daemon this is the intermediate process, which has already
+        // exited after forking the real server.
+        let _ = child.wait();
+        info!("Server ready");
+        return Ok(());
+    }
+
+    // With --daemon a server that is still starting has already left the
+    // child behind; it exits by itself when it finds the ready pipe closed.
+    if polled == 0 {
+        let _ = child.kill();
+    }
+    let status = child.wait();
+
+    if polled > 0 && status.is_ok_and(|status| status.code() == Some(EXIT_ALREADY_RUNNING)) {
+        // Another client started a server at the same moment, so wait for
+        // that one instead.
+        info!("Server already being started, waiting for it");
+        return wait_for_socket(&socket_path(), timeout.saturating_sub(started.elapsed()))
+            .map_err(|_| ClientError::ServerStartTimeout(timeout));
+    }
+
+    let mut output = String::new();
+    stderr.seek(SeekFrom::Start(0))?;
+    stderr.read_to_string(&mut output)?;
+
+    if polled == 0 {
+        Err(ClientError::ServerStartTimeout(timeout))
+    } else {
+        Err(ClientError::ServerStartFailed(output))
+    }
+}
+
+/// An anonymous file for the server's stderr, readable only by this user.
+/// Falls back to a named file, unlinked right away, where the filesystem
+/// does not support `O_TMPFILE`.
+fn stderr_file() -> io::Result<File> {
+    let dir = env::temp_dir();
+    let tmpfile = File::options()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .mode(0o600)
+        .open(&dir);
+    match tmpfile {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP | libc::EISDIR)) => {}
+        result => return result,
+    }
+
+    let path = dir.join(format!(
+        "jobctl-start-{}-{}.log",
+        process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    ));
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// Whether [`start_server`] would start a server listening on
+/// `socket_path`, which it only does for the default path.
+fn can_start_server(socket_path: &Path) -> bool {
+    socket_path == self::socket_path()
+}
+
+/// Wait up to `timeout` for a server to accept connections on `socket_path`.
+fn wait_for_socket(socket_path: &Path, timeout: Duration) -> io::Result<()> {
+    let started = Instant::now();
+    loop {
+        match UnixStream::connect(socket_path) {
+            Ok(_) => return Ok(()),
+            Err(e) if started.elapsed() >= timeout => return Err(e),
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+pub fn send_request(
+    request: ClientRequest,
+    should_start: Option<bool>,
+) -> Result<ServerResponse, ClientError> {
+    send_request_to(&socket_path(), request, should_start, DEFAULT_START_TIMEOUT)
+}
+
+fn send_request_to(
+    socket_path: &Path,
+    request: ClientRequest,
+    should_start: Option<bool>,
+    start_timeout: Duration,
+) -> Result<ServerResponse, ClientError> {
+    let should_start = should_start.unwrap_or(false);
+    let mut stream = match UnixStream::connect(socket_path) {
+        Ok(s) => s,
+        Err(_) => {
+            if should_start && can_start_server(socket_path) {
+                start_server(start_timeout)?;
+                UnixStream::connect(socket_path)?
+            } else {
+                return Err(ClientError::ServerNotRunning);
+            }
+        }
+    };
+    stream.set_read_timeout(Some(CLIENT_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_IO_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let json = serde_json::to_vec(&request)?;
+
+    write_message(&mut stream, &json)?;
+
+    match read_message(&mut reader)? {
+        Some(message) if !message.trim_ascii().is_empty() => Ok(serde_json::from_slice(&message)?),
+        _ => Err(ClientError::EmptyResponse),
+    }
+}
+
+/// Async counterpart of [`JobctlClient`], backed by tokio.
+#[cfg(feature = "async")]
+#[derive(Debug, Clone)]
+pub struct AsyncJobctlClient {
+    cwd: PathBuf,
+    socket_path: PathBuf,
+    start_timeout: Duration,
+}
+
+#[cfg(feature = "async")]
+impl AsyncJobctlClient {
+    /// Create a client that talks to the default socket for the current user.
+    pub fn new(cwd: impl Into<PathBuf>) -> Self {
+        Self {
+            cwd: cwd.into(),
+            socket_path: socket_path(),
+            start_timeout: DEFAULT_START_TIMEOUT,
+        }
+    }
+
+    /// Use a different socket path than the per-user default.
+    ///
+    /// `job-server` only binds the default path itself, so no server is
+    /// auto-started for a different one; it has to be provided some other
+    /// way, such as socket activation.
+    pub fn with_socket_path(mut self, socket_path: impl Into<PathBuf>) -> Self {
+        self.socket_path = socket_path.into();
+        self
+    }
+
+    /// How long to wait for an auto-started server to report it is ready.
+    pub fn with_start_timeout(mut self, timeout: Duration) -> Self {
+        self.start_timeout = timeout;
+        self
+    }
+
+    /// Working directory sent along with every request.
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    /// Socket path this client connects to.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Send an action and return the raw server response.
+    ///
+    /// See [`JobctlClient::request`].
+    pub async fn request(
+        &self,
+        action: Commands,
+        should_start: bool,
+    ) -> Result<ServerResponse, ClientError> {
+        use tokio::io::BufReader;
+        use tokio::net::UnixStream;
+        use tokio::time::timeout;
+
+        let request = ClientRequest {
+            action,
+            cwd: self.cwd.clone(),
+        };
+        let stream = match UnixStream::connect(&self.socket_path).await {
+            Ok(s) => s,
+            Err(_) => {
+                if should_start && can_start_server(&self.socket_path) {
+                    let timeout = self.start_timeout;
+                    tokio::task::spawn_blocking(move || start_server(timeout))
+                        .await
+                        .map_err(|e| ClientError::Connection(io::Error::other(e)))??;
+                    UnixStream::connect(&self.socket_path).await?
+                } else {
+                    return Err(ClientError::ServerNotRunning);
+                }
+            }
+        };
+        let (read_half, mut write_half) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+        let json = serde_json::to_vec(&request)?;
+
+        let exchange = async {
+            write_message_async(&mut write_half, &json).await?;
+            read_message_async(&mut reader).await
+        };
+        let message = timeout(CLIENT_IO_TIMEOUT, exchange)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        match message {
+            Some(message) if !message.trim_ascii().is_empty() => {
+                into_result(serde_json::from_slice(&message)?)
+            }
+            _ => Err(ClientError::EmptyResponse),
+        }
+    }
+
+    /// List every directory that currently has suspended jobs.
+    pub async fn list_sessions(&self) -> Result<Vec<Session>, ClientError> {
+        into_sessions(self.request(list_action(None), false).await?)
+    }
+
+    /// List the jobs belonging to the session for `dir`.
+    pub async fn list_jobs(&self, dir: impl AsRef<Path>) -> Result<Vec<JobOutput>, ClientError> {
+        into_jobs(self.request(list_action(Some(dir.as_ref())), false).await?)
+    }
+
+    /// Record a suspended shell job, starting the server if needed.
+    pub async fn register(&self, pid: u32, number: u8, command: &str) -> Result<Job, ClientError> {
+        into_job(
+            self.request(register_action(pid, number, command), true)
+                .await?,
+        )
+    }
+
+    /// Have the server spawn `command` in the background, starting it if needed.
+    pub async fn run(&self, command: &str) -> Result<Job, ClientError> {
+        self.run_with(command, RunOptions::default()).await
+    }
+
+    /// See [`JobctlClient::run_with`].
+    pub async fn run_with(&self, command: &str, options: RunOptions) -> Result<Job, ClientError> {
+        into_job(self.request(run_action(command, options), true).await?)
+    }
+
+    /// Ask the server to shut down, applying `jobs` to the tracked jobs first.
+    pub async fn kill(&self, jobs: JobsPolicy) -> Result<(), ClientError> {
+        into_kill(self.request(Commands::Kill { jobs }, false).await?)
+    }
+
+    /// See [`JobctlClient::signal`].
+    pub async fn signal(&self, job: &str, signal: i32) -> Result<Job, ClientError> {
+        into_signalled(self.request(signal_action(job, signal), false).await?)
+    }
+
+    /// See [`JobctlClient::freeze`].
+    pub async fn freeze(&self, job: &str) -> Result<(Job, u64), ClientError> {
+        into_frozen(self.request(freeze_action(job), false).await?)
+    }
+
+    /// See [`JobctlClient::inspect`].
+    pub async fn inspect(&self, job: &str, tree: bool) -> Result<Inspection, ClientError> {
+        into_inspection(self.request(inspect_action(job, tree), false).await?)
+    }
+
+    /// See [`JobctlClient::which_port`].
+    pub async fn which_port(&self, port: u16) -> Result<Vec<PortOwner>, ClientError> {
+        into_owners(self.request(which_port_action(port), false).await?)
+    }
+
+    /// See [`JobctlClient::renice`].
+    pub async fn renice(&self, job: &str, nice: i32) -> Result<(Job, u32), ClientError> {
+        into_reniced(self.request(renice_action(job, nice), false).await?)
+    }
+
+    /// See [`JobctlClient::kill_session`].
+    pub async fn kill_session(
+        &self,
+        dir: impl AsRef<Path>,
+        grace: Duration,
+    ) -> Result<Vec<KilledJob>, ClientError> {
+        into_killed(
+            self.request(kill_session_action(dir.as_ref(), grace), false)
+                .await?,
+        )
+    }
+
+    /// See [`JobctlClient::gc`].
+    pub async fn gc(&self, dry_run: bool) -> Result<Vec<StaleJob>, ClientError> {
+        into_stale(self.request(gc_action(dry_run), false).await?)
+    }
+
+    /// See [`JobctlClient::status`].
+    pub async fn status(&self) -> Result<ServerStatus, ClientError> {
+        into_status(self.request(Commands::Status, false).await?)
+    }
+
+    /// See [`JobctlClient::metrics`].
+    pub async fn metrics(&self) -> Result<String, ClientError> {
+        into_metrics(self.request(Commands::Metrics, false).await?)
+    }
+
+    /// See [`JobctlClient::upgrade`].
+    pub async fn upgrade(&self, binary: &Path) -> Result<u32, ClientError> {
+        let action = Commands::Upgrade {
+            binary: binary.to_path_buf(),
+        };
+
+        into_upgrade(self.request(action, false).await?)
+    }
+}
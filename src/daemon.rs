@@ -0,0 +1,371 @@
+use std::env;
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sysinfo::{Pid, ProcessRefreshKind, RefreshKind, System};
+use tracing::warn;
+
+use crate::utils::state_dir;
+
+/// First file descriptor passed by the `sd_listen_fds` protocol.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Exit status of a `job-server` that found another one holding the pidfile.
+pub const EXIT_ALREADY_RUNNING: i32 = 3;
+
+pub fn pidfile_path() -> io::Result<PathBuf> {
+    Ok(state_dir()?.join("job-server.pid"))
+}
+
+pub fn log_dir() -> io::Result<PathBuf> {
+    let dir = state_dir()?.join("logs");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// PID recorded in `path`, if that process is still a running `job-server`.
+pub fn running_pid(path: &Path) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+
+    if pid == std::process::id() {
+        return None;
+    }
+
+    let sys = System::new_with_specifics(
+        RefreshKind::nothing().with_processes(ProcessRefreshKind::nothing()),
+    );
+
+    sys.process(Pid::from(pid as usize))
+        .filter(|process| process.name().to_string_lossy().starts_with("job-server"))
+        .map(|_| pid)
+}
+
+/// Open the pidfile and take an exclusive lock on it, or `None` if another
+/// server holds it. The lock lasts while the file is open, which includes
+/// the daemonized grandchild, and is dropped by the exec of an upgrade.
+pub fn lock_pidfile(path: &Path) -> io::Result<Option<File>> {
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Record this process in the pidfile locked by [`lock_pidfile`].
+pub fn write_pidfile(file: &File) -> io::Result<()> {
+    file.set_len(0)?;
+    file.write_all_at(format!("{}\n", std::process::id()).as_bytes(), 0)
+}
+
+pub fn remove_pidfile(path: &Path) {
+    if fs::read_to_string(path)
+        .is_ok_and(|contents| contents.trim() == std::process::id().to_string())
+    {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Detach from the controlling terminal with the classic double fork.
+///
+/// The original process exits once the first child has been forked, the
+/// child becomes a session leader, and the grandchild (which can never
+/// reacquire a terminal) carries on with stdio pointed at `/dev/null`.
+/// Must be called before any threads are started.
+pub fn daemonize() -> io::Result<()> {
+    fork_and_exit_parent()?;
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    fork_and_exit_parent()?;
+
+    if unsafe { libc::chdir(c"/".as_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let devnull = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_RDWR) };
+    if devnull == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(devnull, fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if devnull > libc::STDERR_FILENO {
+        unsafe { libc::close(devnull) };
+    }
+
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Adopt a listening unix socket passed by systemd socket activation.
+///
+/// Follows the `sd_listen_fds` protocol: `LISTEN_PID` must name this process
+/// and `LISTEN_FDS` must be at least one, in which case fd 3 is the socket.
+/// The variables are removed so spawned jobs do not inherit them. Must be
+/// called before any threads are started.
+pub fn listen_fds_listener() -> io::Result<Option<UnixListener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+
+    unsafe {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let for_us = pid.and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    let count = fds.and_then(|fds| fds.parse::<i32>().ok()).unwrap_or(0);
+
+    if !for_us || count < 1 {
+        return Ok(None);
+    }
+    if count > 1 {
+        warn!(
+            "Expected one socket in LISTEN_FDS, got {}; using fd 3",
+            count
+        );
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+    if !is_unix_listener(fd) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "LISTEN_FDS fd 3 is not a listening unix socket",
+        ));
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Some(unsafe { UnixListener::from_raw_fd(fd) }))
+}
+
+fn is_unix_listener(fd: RawFd) -> bool {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let mut listening: libc::c_int = 0;
+    let mut opt_len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    unsafe {
+        libc::getsockname(
+            fd,
+            (&mut addr as *mut libc::sockaddr_storage).cast(),
+            &mut len,
+        ) == 0
+            && addr.ss_family as libc::c_int == libc::AF_UNIX
+            && libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ACCEPTCONN,
+                (&mut listening as *mut libc::c_int).cast(),
+                &mut opt_len,
+            ) == 0
+            && listening != 0
+    }
+}
+
+/// Adopt a listening socket handed over by a previous server on upgrade.
+pub fn inherited_listener(fd: RawFd) -> io::Result<UnixListener> {
+    if !is_unix_listener(fd) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("fd {} is not a listening unix socket", fd),
+        ));
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+/// Replace this process with `binary`, passing `listener` along as
+/// `--listen-fd`. The PID is unchanged, so pidfiles and service managers keep
+/// tracking the same process. Only returns on failure.
+pub fn exec_upgrade(binary: &Path, listener: &UnixListener, args: &[String]) -> io::Error {
+    let fd = listener.as_raw_fd();
+
+    // The async loop puts the socket in non-blocking mode, which is shared
+    // with the new process through the open file description.
+    if let Err(e) = listener.set_nonblocking(false) {
+        return e;
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } == -1 {
+        return io::Error::last_os_error();
+    }
+
+    Command::new(binary)
+        .args(args)
+        .arg("--listen-fd")
+        .arg(fd.to_string())
+        .exec()
+}
+
+/// Directory systemd reads user units from.
+pub fn systemd_user_dir() -> io::Result<PathBuf> {
+    let dirs = directories::BaseDirs::new()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?;
+    Ok(dirs.config_dir().join("systemd").join("user"))
+}
+
+/// Contents of the `jobctl.socket` user unit.
+pub fn socket_unit(socket_path: &Path) -> String {
+    format!(
+        "[Unit]\n\
+         Description=jobctl job server socket\n\
+         \n\
+         [Socket]\n\
+         ListenStream={}\n\
+         SocketMode=0600\n\
+         \n\
+         [Install]\n\
+         WantedBy=sockets.target\n",
+        socket_path.display()
+    )
+}
+
+/// Contents of the `jobctl.service` user unit started by `jobctl.socket`.
+pub fn service_unit(server_path: &Path) -> String {
+    format!(
+        "[Unit]\n\
+         Description=jobctl job server\n\
+         Requires=jobctl.socket\n\
+         After=jobctl.socket\n\
+         \n\
+         [Service]\n\
+         ExecStart={} --foreground\n\
+         KillMode=process\n",
+        server_path.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixStream;
+
+    /// Run [`activation_case`] in a fresh copy of the test binary, which can
+    /// have fd 3 and the environment to itself.
+    fn run_case(case: &str) {
+        let output = Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "daemon::tests::activation_case",
+                "--ignored",
+                "--test-threads=1",
+            ])
+            .env("JOBCTL_ACTIVATION_CASE", case)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}: {}",
+            case,
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+
+    #[test]
+    #[ignore = "run in a separate process by the socket activation tests"]
+    fn activation_case() {
+        let Ok(case) = env::var("JOBCTL_ACTIVATION_CASE") else {
+            return;
+        };
+        // Hold fd 3 so none of the sockets below is opened there.
+        assert_eq!(unsafe { libc::dup2(0, SD_LISTEN_FDS_START) }, 3);
+
+        let dir = env::temp_dir().join(format!("jobctl-activation-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socket");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (connected, _peer) = UnixStream::pair().unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let passed = match case.as_str() {
+            "adopt" | "other-pid" | "unset" => listener.as_raw_fd(),
+            "connected" => connected.as_raw_fd(),
+            "tcp" => tcp.as_raw_fd(),
+            _ => panic!("unknown case {}", case),
+        };
+        assert_eq!(unsafe { libc::dup2(passed, SD_LISTEN_FDS_START) }, 3);
+        let pid = match case.as_str() {
+            "other-pid" => std::process::id() + 1,
+            _ => std::process::id(),
+        };
+        if case != "unset" {
+            unsafe {
+                env::set_var("LISTEN_PID", pid.to_string());
+                env::set_var("LISTEN_FDS", "1");
+            }
+        }
+
+        let result = listen_fds_listener();
+        assert!(env::var_os("LISTEN_PID").is_none());
+        assert!(env::var_os("LISTEN_FDS").is_none());
+
+        match case.as_str() {
+            "adopt" => {
+                let adopted = result.unwrap().unwrap();
+                assert_eq!(adopted.as_raw_fd(), SD_LISTEN_FDS_START);
+                let flags = unsafe { libc::fcntl(SD_LISTEN_FDS_START, libc::F_GETFD) };
+                assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+
+                let _client = UnixStream::connect(&path).unwrap();
+                adopted.accept().unwrap();
+            }
+            "other-pid" | "unset" => assert!(result.unwrap().is_none()),
+            _ => assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn adopts_the_activated_socket() {
+        run_case("adopt");
+    }
+
+    #[test]
+    fn ignores_fds_meant_for_another_process() {
+        run_case("other-pid");
+    }
+
+    #[test]
+    fn rejects_fds_that_are_not_listening_unix_sockets() {
+        run_case("connected");
+        run_case("tcp");
+    }
+
+    #[test]
+    fn ignores_fds_without_activation() {
+        run_case("unset");
+    }
+}
@@ -0,0 +1,754 @@
+//! Opt-in HTTP API and dashboard, served next to the unix socket.
+//!
+//! Unlike the socket, a TCP port is open to every local user, so each
+//! request must carry the token from [`token_path`], either as an
+//! `Authorization: Bearer` header or a `token` query parameter.
+//!
+//! | Route                | Action                                                   |
+//! |----------------------|----------------------------------------------------------|
+//! | `GET /`              | The dashboard                                            |
+//! | `GET /api/sessions`  | `list`                                                   |
+//! | `GET /api/jobs?dir=` | `list` for one directory                                 |
+//! | `POST /api/run`      | `run`, body `{"command": ..., "cwd": ...}`               |
+//! | `POST /api/signal`   | Signal a tracked job, body `{"pid": ..., "signal": ...}` |
+//! | `GET /api/events`    | Server-sent `sessions` events whenever they change       |
+//! | `GET /metrics`       | `metrics`, as Prometheus text                            |
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{debug, error, info, warn};
+
+use crate::cli::{Commands, RunOptions};
+use crate::protocol::{MAX_MESSAGE_SIZE, SERVER_IO_TIMEOUT};
+use crate::server::{Server, dispatch};
+use crate::sessions::{ClientRequest, ErrorCode, ServerResponse, cleanup_sessions};
+use crate::utils::{lock, parse_signal, state_dir};
+
+const DASHBOARD: &str = include_str!("../resources/dashboard.html");
+
+/// Longest request line or header accepted, in bytes.
+const MAX_HEADER_SIZE: u64 = 8 * 1024;
+/// Most headers accepted in one request.
+const MAX_HEADERS: usize = 64;
+/// Largest request line and headers accepted together, in bytes.
+const MAX_HEADERS_SIZE: usize = 32 * 1024;
+/// Most connections handled at once, including event streams. Further
+/// connections are turned away with 503 until one finishes.
+const MAX_CONNECTIONS: usize = 32;
+
+/// How often event streams check for changes.
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long an event stream may go without writing anything.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// File holding the token HTTP clients must present.
+pub fn token_path() -> io::Result<PathBuf> {
+    Ok(state_dir()?.join("http-token"))
+}
+
+/// Generate a fresh random token and write it to [`token_path`], readable
+/// only by the current user.
+pub fn write_token() -> io::Result<String> {
+    let mut bytes = [0u8; 32];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let path = token_path()?;
+    let _ = fs::remove_file(&path);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(format!("{}\n", token).as_bytes())?;
+
+    Ok(token)
+}
+
+/// Serve the HTTP API on `listener` until the server shuts down.
+pub fn spawn_http(
+    listener: TcpListener,
+    server: Arc<Server>,
+    token: String,
+) -> io::Result<JoinHandle<()>> {
+    let token = Arc::new(token);
+
+    thread::Builder::new().name("http".into()).spawn(move || {
+        let mut handlers: Vec<JoinHandle<()>> = vec![];
+
+        loop {
+            match server.wait_for_connection(&listener) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    error!("HTTP listener error: {}", e);
+                    break;
+                }
+            }
+
+            match listener.accept() {
+                Ok((mut stream, peer)) => {
+                    debug!("HTTP connection from {}", peer);
+
+                    handlers.retain(|handle| !handle.is_finished());
+                    if handlers.len() >= MAX_CONNECTIONS {
+                        warn!("Too many HTTP connections, turning away {}", peer);
+                        let response =
+                            Response::error(503, ErrorCode::Internal, "Too many connections");
+                        let _ = stream.set_write_timeout(Some(SERVER_IO_TIMEOUT));
+                        let _ = write_response(&mut stream, &response);
+                        continue;
+                    }
+
+                    let server = Arc::clone(&server);
+                    let token = Arc::clone(&token);
+                    let spawned =
+                        thread::Builder::new()
+                            .name("http-client".into())
+                            .spawn(move || {
+                                if let Err(e) = handle_connection(stream, &server, &token) {
+                                    debug!("HTTP connection error: {}", e);
+                                }
+                            });
+                    match spawned {
+                        Ok(handle) => handlers.push(handle),
+                        Err(e) => error!("Failed to spawn HTTP handler: {}", e),
+                    }
+                }
+                Err(e) => error!("HTTP accept error: {}", e),
+            }
+        }
+    })
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn token(&self) -> Option<&str> {
+        self.headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| self.query.get("token").map(String::as_str))
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, value: &Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).unwrap(),
+        }
+    }
+
+    fn error(status: u16, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::json(status, &json!({ "code": code, "message": message.into() }))
+    }
+}
+
+fn handle_connection(stream: TcpStream, server: &Server, token: &str) -> io::Result<()> {
+    let _connection = server.connection();
+    stream.set_read_timeout(Some(SERVER_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(SERVER_IO_TIMEOUT))?;
+
+    let mut writer = stream.try_clone()?;
+    let request = match read_request(&mut BufReader::new(stream)) {
+        Ok(request) => request,
+        Err(e) => {
+            let status = match e.get_ref().is_some_and(|e| e.is::<HeadersTooLarge>()) {
+                true => 431,
+                false => 400,
+            };
+            let response = Response::error(status, ErrorCode::InvalidRequest, e.to_string());
+            return write_response(&mut writer, &response);
+        }
+    };
+    info!("HTTP {} {}", request.method, request.path);
+
+    if !request
+        .token()
+        .is_some_and(|given| tokens_match(given, token))
+    {
+        let response = Response::error(401, ErrorCode::InvalidRequest, "Missing or invalid token");
+        return write_response(&mut writer, &response);
+    }
+
+    if request.method == "GET" && request.path == "/api/events" {
+        return stream_events(&mut writer, server);
+    }
+
+    let response = route(&request, server);
+    write_response(&mut writer, &response)
+}
+
+fn route(request: &Request, server: &Server) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: DASHBOARD.as_bytes().to_vec(),
+        },
+        ("GET", "/api/sessions") => call(
+            server,
+            Commands::List {
+                fzf: false,
+                dir: None,
+                sort: None,
+            },
+            "/",
+        ),
+        ("GET", "/api/jobs") => match request.query.get("dir") {
+            Some(dir) => call(
+                server,
+                Commands::List {
+                    fzf: false,
+                    dir: Some(dir.clone()),
+                    sort: None,
+                },
+                "/",
+            ),
+            None => Response::error(400, ErrorCode::InvalidRequest, "Missing `dir` parameter"),
+        },
+        ("POST", "/api/run") => {
+            #[derive(Deserialize)]
+            struct Run {
+                command: String,
+                cwd: PathBuf,
+                #[serde(flatten)]
+                options: RunOptions,
+            }
+
+            match serde_json::from_slice::<Run>(&request.body) {
+                Ok(run) if run.cwd.is_absolute() => call(
+                    server,
+                    Commands::Run {
+                        command: run.command,
+                        options: run.options,
+                    },
+                    run.cwd,
+                ),
+                Ok(_) => Response::error(400, ErrorCode::InvalidRequest, "`cwd` must be absolute"),
+                Err(e) => Response::error(400, ErrorCode::InvalidRequest, e.to_string()),
+            }
+        }
+        ("POST", "/api/signal") => signal(request, server),
+        ("GET", "/metrics") => match request_action(server, Commands::Metrics, "/") {
+            Ok(ServerResponse::Metrics { text }) => Response {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body: text.into_bytes(),
+            },
+            Ok(_) => Response::error(500, ErrorCode::Internal, "Unexpected response"),
+            Err(response) => response,
+        },
+        (
+            _,
+            "/" | "/api/sessions" | "/api/jobs" | "/api/run" | "/api/signal" | "/api/events"
+            | "/metrics",
+        ) => Response::error(405, ErrorCode::InvalidRequest, "Method not allowed"),
+        _ => Response::error(404, ErrorCode::NotFound, "No such endpoint"),
+    }
+}
+
+/// Run `action` through the same dispatcher as the socket and return the
+/// response without its `action` tag.
+fn call(server: &Server, action: Commands, cwd: impl Into<PathBuf>) -> Response {
+    match request_action(server, action, cwd) {
+        Ok(response) => {
+            let mut value = serde_json::to_value(response).unwrap();
+            if let Value::Object(object) = &mut value {
+                object.remove("action");
+            }
+            Response::json(200, &value)
+        }
+        Err(response) => response,
+    }
+}
+
+/// Dispatch `action`, mapping an error response to its HTTP status.
+fn request_action(
+    server: &Server,
+    action: Commands,
+    cwd: impl Into<PathBuf>,
+) -> Result<ServerResponse, Response> {
+    let response = dispatch(
+        ClientRequest {
+            action,
+            cwd: cwd.into(),
+        },
+        server,
+    );
+    server.record_response(&response);
+
+    match response {
+        ServerResponse::Error { code, message } => {
+            let status = match code {
+                ErrorCode::NotFound => 404,
+                ErrorCode::InvalidRequest => 400,
+                ErrorCode::Unsupported => 501,
+                ErrorCode::SpawnFailed | ErrorCode::Internal => 500,
+            };
+            Err(Response::error(status, code, message))
+        }
+        response => Ok(response),
+    }
+}
+
+/// Send a signal to a tracked job, found by PID in whichever session it
+/// belongs to.
+fn signal(request: &Request, server: &Server) -> Response {
+    #[derive(Deserialize)]
+    struct Signal {
+        pid: u32,
+        signal: Value,
+    }
+
+    let body = match serde_json::from_slice::<Signal>(&request.body) {
+        Ok(body) => body,
+        Err(e) => return Response::error(400, ErrorCode::InvalidRequest, e.to_string()),
+    };
+    let signal = match &body.signal {
+        Value::String(name) => parse_signal(name),
+        Value::Number(number) => parse_signal(&number.to_string()),
+        _ => Err("`signal` must be a name or number".to_string()),
+    };
+    let signal = match signal {
+        Ok(signal) => signal,
+        Err(e) => return Response::error(400, ErrorCode::InvalidRequest, e),
+    };
+
+    let directory = lock(&server.store)
+        .iter()
+        .find(|session| session.jobs.iter().any(|job| job.pid == body.pid))
+        .map(|session| session.directory.clone());
+    let Some(directory) = directory else {
+        return Response::error(
+            404,
+            ErrorCode::NotFound,
+            format!("No tracked job with PID {}", body.pid),
+        );
+    };
+
+    call(
+        server,
+        Commands::Signal {
+            job: format!("pid:{}", body.pid),
+            signal,
+        },
+        directory,
+    )
+}
+
+/// Push the session list whenever it changes, until the client goes away
+/// or the server shuts down.
+fn stream_events(writer: &mut TcpStream, server: &Server) -> io::Result<()> {
+    let _subscriber = server.subscriber();
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/event-stream\r\n\
+          Cache-Control: no-store\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+
+    let mut last = String::new();
+    let mut last_write = Instant::now();
+
+    while server.shutdown_reason().is_none() {
+        let sessions = serde_json::to_string(&cleanup_sessions(&server.store)).unwrap();
+
+        if sessions != last {
+            writer.write_all(format!("event: sessions\ndata: {}\n\n", sessions).as_bytes())?;
+            last = sessions;
+            last_write = Instant::now();
+        } else if last_write.elapsed() >= KEEPALIVE_INTERVAL {
+            writer.write_all(b": keepalive\n\n")?;
+            last_write = Instant::now();
+        }
+
+        if hung_up(writer, EVENT_INTERVAL)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Wait up to `timeout` for the peer to close its end of `stream`.
+fn hung_up(stream: &TcpStream, timeout: Duration) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+
+    match unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(err),
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(fd.revents & (libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR) != 0),
+    }
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+    let line = read_line(reader)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid(format!("Malformed request line `{}`", line)));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid(format!("Unsupported protocol `{}`", version)));
+    }
+
+    let mut headers = HashMap::new();
+    let mut count = 0;
+    let mut size = line.len();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        count += 1;
+        size += line.len();
+        if count > MAX_HEADERS {
+            return Err(too_large(format!("More than {} headers", MAX_HEADERS)));
+        }
+        if size > MAX_HEADERS_SIZE {
+            return Err(too_large(format!(
+                "Headers exceed {} bytes",
+                MAX_HEADERS_SIZE
+            )));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid(format!("Malformed header `{}`", line)));
+        };
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let length: usize = match headers.get("content-length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| invalid("Invalid Content-Length".to_string()))?,
+        None => 0,
+    };
+    if length > MAX_MESSAGE_SIZE {
+        return Err(invalid(format!(
+            "Request body exceeds {} bytes",
+            MAX_MESSAGE_SIZE
+        )));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect();
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        headers,
+        body,
+    })
+}
+
+/// Read one CRLF-terminated line of at most [`MAX_HEADER_SIZE`] bytes.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_HEADER_SIZE + 1)
+        .read_until(b'\n', &mut line)?;
+
+    if line.len() as u64 > MAX_HEADER_SIZE {
+        return Err(too_large("Header line too long".to_string()));
+    }
+    if !line.ends_with(b"\n") {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    String::from_utf8(line)
+        .map(|line| line.trim_end_matches(['\r', '\n']).to_string())
+        .map_err(|_| invalid("Header is not valid UTF-8".to_string()))
+}
+
+fn write_response(writer: &mut TcpStream, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+/// Decode `%XX` escapes and `+` in a query string component.
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Compare tokens without returning early on the first mismatch.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Marks a request rejected for the size of its headers, which is answered
+/// with 431 rather than 400.
+#[derive(Debug)]
+struct HeadersTooLarge(String);
+
+impl fmt::Display for HeadersTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for HeadersTooLarge {}
+
+fn too_large(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, HeadersTooLarge(message))
+}
+
+/// Warn when the HTTP API is reachable from other machines.
+pub fn check_address(listener: &TcpListener) {
+    match listener.local_addr() {
+        Ok(addr) if !addr.ip().is_loopback() => {
+            warn!("HTTP API is listening on non-loopback address {}", addr)
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &[u8]) -> io::Result<Request> {
+        read_request(&mut io::BufReader::new(raw))
+    }
+
+    fn rejected(raw: &[u8]) -> io::ErrorKind {
+        match parse(raw) {
+            Ok(request) => panic!("accepted {} {}", request.method, request.path),
+            Err(e) => e.kind(),
+        }
+    }
+
+    fn too_large(raw: &[u8]) -> bool {
+        match parse(raw) {
+            Ok(request) => panic!("accepted {} {}", request.method, request.path),
+            Err(e) => e.get_ref().is_some_and(|e| e.is::<HeadersTooLarge>()),
+        }
+    }
+
+    fn with_headers(count: usize, value: &str) -> Vec<u8> {
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..count {
+            raw.extend(format!("X-Header-{}: {}\r\n", i, value).bytes());
+        }
+        raw.extend(b"\r\n");
+        raw
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("plain"), "plain");
+        assert_eq!(percent_decode("%2Fhome%2Fme"), "/home/me");
+        assert_eq!(percent_decode("a+b%20c"), "a b c");
+        assert_eq!(percent_decode("%C3%A9t%C3%A9"), "été");
+        assert_eq!(percent_decode("%2b"), "+");
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("%+1"), "% 1");
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn reads_requests() {
+        let request = parse(
+            b"POST /api/signal?dir=%2Ftmp%2Fa+b&token=abc&flag HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Content-Type: application/json\r\n\
+              Content-Length: 11\r\n\
+              \r\n\
+              {\"pid\": 42}trailing",
+        )
+        .unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/signal");
+        assert_eq!(request.query["dir"], "/tmp/a b");
+        assert_eq!(request.query["flag"], "");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(request.body, b"{\"pid\": 42}");
+        assert_eq!(request.token(), Some("abc"));
+    }
+
+    #[test]
+    fn prefers_the_authorization_header() {
+        let request =
+            parse(b"GET /api/sessions?token=query HTTP/1.0\nAuthorization: Bearer header\n\n")
+                .unwrap();
+        assert_eq!(request.token(), Some("header"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(rejected(b"GET /\r\n\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(
+            rejected(b"GET / SPDY/3\r\n\r\n"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            rejected(b"GET / HTTP/1.1\r\nNo colon\r\n\r\n"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            rejected(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            rejected(b"GET / HTTP/1.1\r\nHost: x"),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            rejected(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn limits_request_size() {
+        let too_long = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_MESSAGE_SIZE + 1
+        );
+        assert_eq!(rejected(too_long.as_bytes()), io::ErrorKind::InvalidData);
+
+        let long_header = format!(
+            "GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(MAX_HEADER_SIZE as usize)
+        );
+        assert!(too_large(long_header.as_bytes()));
+        assert!(!too_large(b"GET / HTTP/1.1\r\nNo colon\r\n\r\n"));
+    }
+
+    #[test]
+    fn limits_headers() {
+        assert_eq!(
+            parse(&with_headers(MAX_HEADERS, "x"))
+                .unwrap()
+                .headers
+                .len(),
+            MAX_HEADERS
+        );
+        assert!(too_large(&with_headers(MAX_HEADERS + 1, "x")));
+
+        // Each header is short enough on its own, but not all together.
+        let value = "a".repeat(4 * 1024);
+        assert!(parse(&with_headers(7, &value)).is_ok());
+        assert!(too_large(&with_headers(8, &value)));
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc124", "abc123"));
+        assert!(!tokens_match("abc", "abc123"));
+        assert!(!tokens_match("", "abc123"));
+    }
+}
@@ -0,0 +1,381 @@
+//! JSON-RPC 2.0 front end for the socket protocol.
+//!
+//! Method names are the snake_case `action` names of [`Commands`] and params
+//! are that variant's fields, plus an optional `cwd` (defaulting to `/`).
+//! Results are the matching [`ServerResponse`] without its `action` tag.
+//!
+//! [`Commands`]: crate::cli::Commands
+
+use serde_json::{Map, Value, json};
+
+use crate::server::{Server, dispatch};
+use crate::sessions::{ClientRequest, ErrorCode, ServerResponse};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+pub const NOT_FOUND: i64 = -32001;
+pub const SPAWN_FAILED: i64 = -32002;
+
+/// Methods callable over JSON-RPC and the names of their params.
+pub const METHODS: &[(&str, &[&str])] = &[
+    ("list", &["dir"]),
+    ("register", &["pid", "number", "command"]),
+    (
+        "run",
+        &[
+            "command",
+            "nice",
+            "ionice",
+            "max_mem",
+            "max_cpu_seconds",
+            "max_files",
+        ],
+    ),
+    ("kill", &["jobs"]),
+    ("gc", &["dry_run"]),
+    ("signal", &["job", "signal"]),
+    ("freeze", &["job"]),
+    ("inspect", &["job", "tree"]),
+    ("which_port", &["port"]),
+    ("renice", &["job", "nice"]),
+    ("kill_session", &["dir", "grace_ms"]),
+    ("status", &[]),
+    ("upgrade", &["binary"]),
+    ("metrics", &[]),
+];
+
+/// Whether the first message on a connection is JSON-RPC rather than the
+/// native `{"action": ...}` format.
+pub fn is_jsonrpc(message: &[u8]) -> bool {
+    match serde_json::from_slice::<Value>(message) {
+        Ok(Value::Object(object)) => object.contains_key("jsonrpc"),
+        Ok(Value::Array(_)) => true,
+        _ => false,
+    }
+}
+
+/// Handle one JSON-RPC message (a single call or a batch) and return the
+/// encoded reply, or `None` if it only contained notifications.
+pub fn handle_message(message: &[u8], server: &Server) -> Option<Vec<u8>> {
+    let reply = match serde_json::from_slice::<Value>(message) {
+        Ok(Value::Array(calls)) if calls.is_empty() => {
+            Some(error(Value::Null, INVALID_REQUEST, "Empty batch", None))
+        }
+        Ok(Value::Array(calls)) => {
+            let replies: Vec<Value> = calls
+                .into_iter()
+                .filter_map(|call| handle_call(call, server))
+                .collect();
+            (!replies.is_empty()).then_some(Value::Array(replies))
+        }
+        Ok(call) => handle_call(call, server),
+        Err(e) => Some(error(Value::Null, PARSE_ERROR, &e.to_string(), None)),
+    };
+
+    reply.map(|reply| serde_json::to_vec(&reply).unwrap())
+}
+
+fn handle_call(call: Value, server: &Server) -> Option<Value> {
+    let Value::Object(mut call) = call else {
+        return Some(error(
+            Value::Null,
+            INVALID_REQUEST,
+            "Expected an object",
+            None,
+        ));
+    };
+
+    let id = call.remove("id");
+    let is_notification = id.is_none();
+    let id = id.unwrap_or(Value::Null);
+
+    if call.get("jsonrpc") != Some(&Value::from("2.0")) {
+        return Some(error(id, INVALID_REQUEST, "jsonrpc must be \"2.0\"", None));
+    }
+    let Some(Value::String(method)) = call.remove("method") else {
+        return Some(error(id, INVALID_REQUEST, "method must be a string", None));
+    };
+    let params = match call.remove("params") {
+        None | Some(Value::Null) => Map::new(),
+        Some(Value::Object(params)) => params,
+        Some(_) => {
+            return Some(error(id, INVALID_PARAMS, "params must be an object", None));
+        }
+    };
+
+    let reply = match method.as_str() {
+        "rpc.discover" => success(id, discover()),
+        _ if !METHODS.iter().any(|(name, _)| *name == method) => error(
+            id,
+            METHOD_NOT_FOUND,
+            &format!("Unknown method `{}`", method),
+            None,
+        ),
+        _ => match to_request(&method, params) {
+            Ok(req) => {
+                let response = dispatch(req, server);
+                server.record_response(&response);
+
+                match response {
+                    ServerResponse::Error { code, message } => error(
+                        id,
+                        error_code(code),
+                        &message,
+                        Some(json!({ "code": code })),
+                    ),
+                    response => success(id, result(response)),
+                }
+            }
+            Err(message) => error(id, INVALID_PARAMS, &message, None),
+        },
+    };
+
+    (!is_notification).then_some(reply)
+}
+
+fn to_request(method: &str, mut params: Map<String, Value>) -> Result<ClientRequest, String> {
+    params.entry("cwd").or_insert_with(|| Value::from("/"));
+    params.insert("action".to_string(), Value::from(method));
+
+    serde_json::from_value(Value::Object(params)).map_err(|e| e.to_string())
+}
+
+fn result(response: ServerResponse) -> Value {
+    let mut value = serde_json::to_value(response).unwrap();
+    if let Value::Object(object) = &mut value {
+        object.remove("action");
+    }
+    value
+}
+
+fn error_code(code: ErrorCode) -> i64 {
+    match code {
+        ErrorCode::NotFound => NOT_FOUND,
+        ErrorCode::SpawnFailed => SPAWN_FAILED,
+        ErrorCode::InvalidRequest => INVALID_PARAMS,
+        ErrorCode::Unsupported => METHOD_NOT_FOUND,
+        ErrorCode::Internal => INTERNAL_ERROR,
+    }
+}
+
+/// Minimal OpenRPC document listing the available methods.
+fn discover() -> Value {
+    let methods: Vec<Value> = METHODS
+        .iter()
+        .map(|(name, params)| {
+            let params: Vec<Value> = params
+                .iter()
+                .chain(&["cwd"])
+                .map(|param| json!({ "name": param, "schema": {} }))
+                .collect();
+            json!({ "name": name, "params": params, "result": { "name": "result", "schema": {} } })
+        })
+        .collect();
+
+    json!({
+        "openrpc": "1.2.6",
+        "info": { "title": "jobctl", "version": env!("CARGO_PKG_VERSION") },
+        "methods": methods,
+    })
+}
+
+fn success(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "result": result, "id": id })
+}
+
+fn error(id: Value, code: i64, message: &str, data: Option<Value>) -> Value {
+    let mut error = json!({ "code": code, "message": message });
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn server() -> Server {
+        Server::new(vec![], PathBuf::from("/nonexistent/jobctl.sock")).unwrap()
+    }
+
+    fn call(server: &Server, message: &str) -> Option<Value> {
+        handle_message(message.as_bytes(), server)
+            .map(|reply| serde_json::from_slice(&reply).unwrap())
+    }
+
+    fn error_code_of(reply: &Value) -> i64 {
+        reply["error"]["code"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn answers_calls() {
+        let reply = call(&server(), r#"{"jsonrpc":"2.0","method":"status","id":7}"#).unwrap();
+        assert_eq!(reply["jsonrpc"], "2.0");
+        assert_eq!(reply["id"], 7);
+        assert_eq!(reply["result"]["status"]["sessions"], 0);
+        assert!(reply["result"].get("action").is_none());
+        assert!(reply.get("error").is_none());
+    }
+
+    #[test]
+    fn keeps_string_and_null_ids() {
+        let server = server();
+        let reply = call(&server, r#"{"jsonrpc":"2.0","method":"status","id":"abc"}"#).unwrap();
+        assert_eq!(reply["id"], "abc");
+
+        // An explicit null id is a call, not a notification.
+        let reply = call(&server, r#"{"jsonrpc":"2.0","method":"status","id":null}"#).unwrap();
+        assert_eq!(reply["id"], Value::Null);
+        assert!(reply.get("result").is_some());
+    }
+
+    #[test]
+    fn does_not_answer_notifications() {
+        let server = server();
+        assert_eq!(
+            call(&server, r#"{"jsonrpc":"2.0","method":"status"}"#),
+            None
+        );
+        // Not even when they fail.
+        assert_eq!(call(&server, r#"{"jsonrpc":"2.0","method":"nope"}"#), None);
+        assert_eq!(
+            call(
+                &server,
+                r#"{"jsonrpc":"2.0","method":"list","params":{"dir":"/nowhere"}}"#
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn answers_batches() {
+        let reply = call(
+            &server(),
+            r#"[
+                {"jsonrpc":"2.0","method":"status","id":1},
+                {"jsonrpc":"2.0","method":"status"},
+                {"jsonrpc":"2.0","method":"nope","id":2},
+                5
+            ]"#,
+        )
+        .unwrap();
+
+        let replies = reply.as_array().unwrap();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["id"], 1);
+        assert!(replies[0].get("result").is_some());
+        assert_eq!(replies[1]["id"], 2);
+        assert_eq!(error_code_of(&replies[1]), METHOD_NOT_FOUND);
+        assert_eq!(replies[2]["id"], Value::Null);
+        assert_eq!(error_code_of(&replies[2]), INVALID_REQUEST);
+    }
+
+    #[test]
+    fn does_not_answer_batches_of_notifications() {
+        assert_eq!(
+            call(
+                &server(),
+                r#"[{"jsonrpc":"2.0","method":"status"},{"jsonrpc":"2.0","method":"metrics"}]"#
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_empty_batches() {
+        let reply = call(&server(), "[]").unwrap();
+        assert_eq!(error_code_of(&reply), INVALID_REQUEST);
+        assert_eq!(reply["id"], Value::Null);
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let reply = call(&server(), r#"{"jsonrpc":"2.0","method":"#).unwrap();
+        assert_eq!(error_code_of(&reply), PARSE_ERROR);
+        assert_eq!(reply["id"], Value::Null);
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let server = server();
+        for message in [
+            r#""status""#,
+            r#"{"method":"status","id":1}"#,
+            r#"{"jsonrpc":"1.0","method":"status","id":1}"#,
+            r#"{"jsonrpc":"2.0","method":5,"id":1}"#,
+            r#"{"jsonrpc":"2.0","id":1}"#,
+        ] {
+            let reply = call(&server, message).unwrap();
+            assert_eq!(error_code_of(&reply), INVALID_REQUEST, "{}", message);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_methods() {
+        let reply = call(&server(), r#"{"jsonrpc":"2.0","method":"explode","id":1}"#).unwrap();
+        assert_eq!(error_code_of(&reply), METHOD_NOT_FOUND);
+        assert!(
+            reply["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("explode")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_params() {
+        let server = server();
+        for message in [
+            r#"{"jsonrpc":"2.0","method":"status","params":[1],"id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"signal","params":{"job":"%1"},"id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"renice","params":{"job":"%1","nice":"low"},"id":1}"#,
+        ] {
+            let reply = call(&server, message).unwrap();
+            assert_eq!(error_code_of(&reply), INVALID_PARAMS, "{}", message);
+        }
+    }
+
+    #[test]
+    fn maps_server_errors() {
+        let reply = call(
+            &server(),
+            r#"{"jsonrpc":"2.0","method":"list","params":{"dir":"/nowhere"},"id":3}"#,
+        )
+        .unwrap();
+        assert_eq!(error_code_of(&reply), NOT_FOUND);
+        assert_eq!(reply["error"]["data"]["code"], "not_found");
+        assert_eq!(reply["id"], 3);
+    }
+
+    #[test]
+    fn describes_methods() {
+        let reply = call(
+            &server(),
+            r#"{"jsonrpc":"2.0","method":"rpc.discover","id":1}"#,
+        )
+        .unwrap();
+        let methods = reply["result"]["methods"].as_array().unwrap();
+        assert_eq!(methods.len(), METHODS.len());
+        let run = methods.iter().find(|m| m["name"] == "run").unwrap();
+        let params: Vec<&str> = run["params"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["name"].as_str().unwrap())
+            .collect();
+        assert!(params.contains(&"command") && params.contains(&"cwd"));
+    }
+
+    #[test]
+    fn detects_jsonrpc_messages() {
+        assert!(is_jsonrpc(br#"{"jsonrpc":"2.0","method":"status"}"#));
+        assert!(is_jsonrpc(b"[]"));
+        assert!(!is_jsonrpc(br#"{"action":"status","cwd":"/"}"#));
+        assert!(!is_jsonrpc(b"not json"));
+    }
+}
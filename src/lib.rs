@@ -1,5 +1,15 @@
 pub mod cli;
+pub mod client;
+pub mod daemon;
+pub mod http;
+pub mod jsonrpc;
+pub mod metrics;
+pub mod policy;
+pub mod protocol;
+pub mod server;
 pub mod sessions;
+pub mod snapshot;
+pub mod sockets;
 pub mod utils;
 
 #[derive(Debug)]
@@ -14,19 +24,48 @@ pub enum ClientError {
     Connection(std::io::Error),
     Serialization(serde_json::Error),
     ServerNotRunning,
+    ServerStartFailed(String),
+    ServerStartTimeout(std::time::Duration),
     EmptyResponse,
     InvalidResponse(String),
+    NotFound(String),
+    SpawnFailed(String),
+    InvalidRequest(String),
+    Unsupported(String),
     ServerError(String),
 }
 
+impl ClientError {
+    /// Map an error response from the server to the matching variant.
+    pub fn from_server(code: sessions::ErrorCode, message: String) -> Self {
+        match code {
+            sessions::ErrorCode::NotFound => ClientError::NotFound(message),
+            sessions::ErrorCode::SpawnFailed => ClientError::SpawnFailed(message),
+            sessions::ErrorCode::InvalidRequest => ClientError::InvalidRequest(message),
+            sessions::ErrorCode::Unsupported => ClientError::Unsupported(message),
+            sessions::ErrorCode::Internal => ClientError::ServerError(message),
+        }
+    }
+}
+
 impl std::fmt::Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ClientError::Connection(err) => write!(f, "Connection error: {}", err),
             ClientError::Serialization(err) => write!(f, "Serialization error: {}", err),
             ClientError::ServerNotRunning => write!(f, "Server not running, no sessions found"),
+            ClientError::ServerStartFailed(stderr) => {
+                write!(f, "Server failed to start: {}", stderr.trim())
+            }
+            ClientError::ServerStartTimeout(timeout) => {
+                write!(f, "Server did not become ready within {:?}", timeout)
+            }
             ClientError::EmptyResponse => write!(f, "Received empty response from server"),
             ClientError::InvalidResponse(msg) => write!(f, "Invalid response: {}", msg),
+            ClientError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            ClientError::SpawnFailed(msg) => write!(f, "Spawn failed: {}", msg),
+            ClientError::InvalidRequest(msg) => write!(f, "Invalid request: {}", msg),
+            ClientError::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
             ClientError::ServerError(msg) => write!(f, "Server error: {}", msg),
         }
     }
@@ -45,4 +84,3 @@ impl From<serde_json::Error> for ClientError {
         ClientError::Serialization(err)
     }
 }
-
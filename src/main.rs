@@ -1,24 +1,314 @@
 use clap::Parser;
-use jobctl::cli::{Cli, Commands, ZSH};
-use jobctl::sessions::{ClientRequest, ServerResponse};
-use jobctl::utils::{build_fzf_jobs_input, build_fzf_sessions_input, run_fzf_cmd};
-use std::{env, process};
+use jobctl::cli::{Cli, Commands, ServerCommands, SessionCommands, ZSH};
+use jobctl::client::JobctlClient;
+use jobctl::daemon::{service_unit, socket_unit, systemd_user_dir};
+use jobctl::policy::PolicyAction;
+use jobctl::sessions::{
+    Inspection, JobState, KillOutcome, KilledJob, PortOwner, SCHEMA_TYPES, ServerResponse,
+    ServerStatus, StaleJob, schema,
+};
+use jobctl::utils::{
+    background_warnings, build_fzf_jobs_input, build_fzf_sessions_input, format_bytes,
+    format_duration, format_timestamp, own_terminal, run_fzf_cmd, shell_quote, socket_path,
+    terminal_fds, time_ago,
+};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
 use sysinfo::{Pid, ProcessRefreshKind, RefreshKind, System};
 
 use jobctl::ClientError;
 
-fn handle_response(response: Result<serde_json::Value, ClientError>) {
-    match response {
-        Ok(res) => {
-            println!("{}", serde_json::to_string_pretty(&res).unwrap());
-        }
+/// Exit status for each kind of failure, so scripts can tell them apart.
+fn exit_code(error: &ClientError) -> i32 {
+    match error {
+        ClientError::InvalidRequest(_) => 2,
+        ClientError::Connection(_)
+        | ClientError::ServerNotRunning
+        | ClientError::ServerStartFailed(_)
+        | ClientError::ServerStartTimeout(_) => 3,
+        ClientError::NotFound(_) => 4,
+        ClientError::SpawnFailed(_) => 5,
+        ClientError::Unsupported(_) => 6,
+        ClientError::Serialization(_)
+        | ClientError::EmptyResponse
+        | ClientError::InvalidResponse(_)
+        | ClientError::ServerError(_) => 1,
+    }
+}
+
+fn exit_on_error<T>(result: Result<T, ClientError>) -> T {
+    match result {
+        Ok(value) => value,
         Err(e) => {
             eprintln!("Error: {}", e);
-            process::exit(1);
+            process::exit(exit_code(&e));
+        }
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+fn print_status(status: &ServerStatus) {
+    println!("job-server {} (pid {})", status.version, status.pid);
+    println!("socket:      {}", status.socket_path.display());
+    println!("uptime:      {}", format_duration(status.uptime));
+    println!("sessions:    {} ({} jobs)", status.sessions, status.jobs);
+    println!("connections: {}", status.connections);
+    println!("subscribers: {}", status.subscribers);
+    println!(
+        "last error:  {}",
+        status.last_error.as_deref().unwrap_or("none")
+    );
+}
+
+fn print_killed(dir: &Path, killed: &[KilledJob]) {
+    println!("Killed session {}", dir.display());
+    for k in killed {
+        let outcome = match k.outcome {
+            KillOutcome::Gone => "already exited",
+            KillOutcome::Terminated => "terminated",
+            KillOutcome::Killed => "killed after grace period",
+            KillOutcome::Survived => "still running after SIGKILL",
+            KillOutcome::Failed => "could not be signalled",
+        };
+        print!(
+            "  %{} {} {}: {}",
+            k.job.number, k.job.pid, k.job.command, outcome
+        );
+        match &k.error {
+            Some(error) => println!(" ({})", error),
+            None => println!(),
         }
     }
 }
 
+fn print_stale(stale: &[StaleJob], dry_run: bool) {
+    if stale.is_empty() {
+        println!("No jobs affected by stale job policies");
+        return;
+    }
+
+    for entry in stale {
+        let action = match (entry.action, dry_run) {
+            (PolicyAction::Warn, true) => "would warn",
+            (PolicyAction::Freeze, true) => "would freeze",
+            (PolicyAction::Term, true) => "would terminate",
+            (PolicyAction::Kill, true) => "would kill",
+            (PolicyAction::Warn, false) => "warned",
+            (PolicyAction::Freeze, false) => "froze",
+            (PolicyAction::Term, false) => "terminated",
+            (PolicyAction::Kill, false) => "killed",
+        };
+        println!(
+            "{:<15} %{} {} {} in {} (suspended {})",
+            action,
+            entry.job.number,
+            entry.job.pid,
+            entry.job.command,
+            entry.directory.display(),
+            format_duration(entry.suspended_for)
+        );
+    }
+}
+
+/// fzf preview command that inspects the job on the selected line, which
+/// starts with `[number:pid]`. It selects by `pid:N`, since a bare number is
+/// taken as a job number first.
+fn inspect_preview(dir: &str) -> Option<String> {
+    let jobctl = env::current_exe().ok()?;
+    Some(format!(
+        "cd {} && {} inspect \"pid:$(printf %s {{}} | cut -d: -f2 | cut -d] -f1)\"",
+        shell_quote(dir),
+        shell_quote(&jobctl.to_string_lossy())
+    ))
+}
+
+fn print_inspection(inspection: &Inspection) {
+    let job = &inspection.job;
+    let usage = &inspection.usage;
+    let unknown = || "unknown".to_string();
+    let path = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .map_or_else(unknown, |p| p.display().to_string())
+    };
+
+    println!("%{} {} {}", job.number, job.pid, job.command);
+    println!("directory:   {}", inspection.directory.display());
+    println!(
+        "pid:         {} (pgid {}, sid {})",
+        job.pid, inspection.pgid, inspection.sid
+    );
+    println!(
+        "shell:       {} ({})",
+        inspection.shell.as_deref().unwrap_or("unknown"),
+        inspection.shell_pid
+    );
+    println!("tty:         {}", path(&inspection.tty));
+    println!(
+        "command:     {}",
+        inspection.cmdline.as_deref().unwrap_or(&job.command)
+    );
+    println!("exe:         {}", path(&inspection.exe));
+    println!("cwd:         {}", path(&inspection.cwd));
+    if let Some(started) = inspection.started {
+        println!(
+            "started:     {} ({})",
+            format_timestamp(started),
+            time_ago(started)
+        );
+    }
+    println!(
+        "suspended:   {} ({})",
+        format_timestamp(job.suspended),
+        time_ago(job.suspended)
+    );
+    for (i, change) in job.history.iter().enumerate() {
+        println!(
+            "{:<13}{} {}",
+            if i == 0 { "history:" } else { "" },
+            format_timestamp(change.at),
+            state_name(change.state)
+        );
+    }
+    println!("state:       {}", state_name(job.state));
+    println!(
+        "memory:      {} resident, {} virtual",
+        format_bytes(usage.rss),
+        format_bytes(usage.virtual_memory)
+    );
+    println!("cpu time:    {:.1}s", usage.cpu_time_ms as f64 / 1000.0);
+    println!(
+        "processes:   {} descendants, {} threads",
+        usage.children, usage.threads
+    );
+    match inspection.open_files {
+        Some(count) => println!("open files:  {}", count),
+        None => println!("open files:  unknown"),
+    }
+    for (i, socket) in inspection.sockets.iter().enumerate() {
+        println!(
+            "{:<13}{:<5} {} {} (pid {})",
+            if i == 0 { "sockets:" } else { "" },
+            socket.protocol,
+            socket.address,
+            socket.state,
+            socket.pid
+        );
+    }
+    if job.frozen != 0 {
+        println!(
+            "frozen:      {} ({} reclaimed in total)",
+            format_timestamp(job.frozen),
+            format_bytes(job.reclaimed)
+        );
+    }
+
+    let Some(tree) = &inspection.tree else {
+        return;
+    };
+    println!();
+    println!("{:>8}  {:<10} COMMAND", "PID", "STATE");
+    for node in tree {
+        print!(
+            "{:>8}  {:<10} {}{}",
+            node.pid,
+            node.state,
+            "  ".repeat(node.depth as usize),
+            node.command
+        );
+        match node.still_running {
+            true => println!("  [still running]"),
+            false => println!(),
+        }
+    }
+
+    let running = tree.iter().filter(|node| node.still_running).count();
+    if running > 0 {
+        println!();
+        println!(
+            "Warning: {} process(es) kept running while the job is stopped",
+            running
+        );
+    }
+}
+
+fn print_owners(port: u16, owners: &[PortOwner]) {
+    if owners.is_empty() {
+        println!("Nothing is listening on port {}", port);
+        return;
+    }
+
+    for owner in owners {
+        let socket = &owner.socket;
+        match (&owner.job, &owner.directory) {
+            (Some(job), Some(directory)) => println!(
+                "{} {}: %{} {} {} in {} (pid {}: {})",
+                socket.protocol,
+                socket.address,
+                job.number,
+                job.pid,
+                job.command,
+                directory.display(),
+                socket.pid,
+                owner.command
+            ),
+            _ => println!(
+                "{} {}: untracked process {} {}",
+                socket.protocol, socket.address, socket.pid, owner.command
+            ),
+        }
+    }
+}
+
+fn state_name(state: JobState) -> &'static str {
+    match state {
+        JobState::Stopped => "stopped",
+        JobState::Running => "running",
+    }
+}
+
+fn install_service(force: bool) -> std::io::Result<()> {
+    let server_path = env::current_exe()?.with_file_name("job-server");
+    let unit_dir = systemd_user_dir()?;
+    let units = [
+        ("jobctl.socket", socket_unit(&socket_path())),
+        ("jobctl.service", service_unit(&server_path)),
+    ];
+
+    fs::create_dir_all(&unit_dir)?;
+
+    if !force
+        && let Some(path) = units
+            .iter()
+            .map(|(name, _)| unit_dir.join(name))
+            .find(|path| path.exists())
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!(
+                "{} already exists, pass --force to overwrite",
+                path.display()
+            ),
+        ));
+    }
+
+    for (name, contents) in units {
+        let path = unit_dir.join(name);
+        fs::write(&path, contents)?;
+        println!("Wrote {}", path.display());
+    }
+
+    println!(
+        "Enable with: systemctl --user daemon-reload && systemctl --user enable --now jobctl.socket"
+    );
+
+    Ok(())
+}
+
 fn main() -> std::io::Result<()> {
     let cli = Cli::parse();
     let cwd = env::current_dir().expect("Failed to get current directory");
@@ -32,55 +322,56 @@ fn main() -> std::io::Result<()> {
 
     tracing_subscriber::fmt().with_max_level(level).init();
 
+    let client = JobctlClient::new(cwd).with_start_timeout(cli.start_timeout);
+
     match &cli.command {
-        Some(Commands::List { fzf, dir }) => {
-            let request = ClientRequest {
-                action: Commands::List {
-                    fzf: *fzf,
-                    dir: dir.clone(),
-                },
-                cwd,
-            };
-            let response = jobctl::sessions::send_request(request, None);
-
-            if *fzf {
-                match response {
-                    Ok(res) => match serde_json::from_value::<ServerResponse>(res) {
-                        Ok(ServerResponse::ListJobs { jobs }) => {
-                            let (jobs_map, input) = build_fzf_jobs_input(jobs);
-
-                            if let Ok(selected) = run_fzf_cmd(&input) {
-                                let job_number = jobs_map
-                                    .iter()
-                                    .find(|(_, v)| *v.trim().to_string() == selected)
-                                    .map(|(k, _)| k);
-                                if let Some(job) = job_number {
-                                    println!("fg %{}", job)
-                                } else {
-                                    eprintln!("Error: Selected job not found");
-                                }
-                            }
-                        }
-                        Ok(ServerResponse::ListSessions { sessions }) => {
-                            let directories = build_fzf_sessions_input(sessions);
-
-                            if let Ok(selected) = run_fzf_cmd(&directories) {
-                                println!("cd {}", selected)
-                            }
-                        }
-                        Ok(_) => eprintln!("Error: Unexpected response format"),
-                        Err(e) => eprintln!("Error parsing response: {}", e),
-                    },
-                    Err(e) => {
-                        eprintln!("Error: {}", e);
-                        process::exit(1);
+        Some(Commands::List { fzf, dir, sort }) => match dir {
+            Some(dir) => {
+                let mut jobs = exit_on_error(client.list_jobs(dir));
+                if let Some(key) = sort {
+                    jobs.sort_by_key(|job| Reverse(key.value(&job.usage)));
+                }
+
+                if !*fzf {
+                    print_json(&ServerResponse::ListJobs { jobs });
+                    return Ok(());
+                }
+
+                let (jobs_map, input) = build_fzf_jobs_input(jobs);
+
+                let preview = inspect_preview(dir);
+                if let Ok(selected) = run_fzf_cmd(&input, preview.as_deref()) {
+                    let job_number = jobs_map
+                        .iter()
+                        .find(|(_, v)| *v.trim().to_string() == selected)
+                        .map(|(k, _)| k);
+                    if let Some(job) = job_number {
+                        println!("fg %{}", job)
+                    } else {
+                        eprintln!("Error: Selected job not found");
                     }
                 }
-                process::exit(0);
             }
+            None => {
+                let mut sessions = exit_on_error(client.list_sessions());
+                if let Some(key) = sort {
+                    sessions.sort_by_key(|session| {
+                        Reverse(key.value(&session.usage.unwrap_or_default()))
+                    });
+                }
 
-            handle_response(response);
-        }
+                if !*fzf {
+                    print_json(&ServerResponse::ListSessions { sessions });
+                    return Ok(());
+                }
+
+                let directories = build_fzf_sessions_input(sessions);
+
+                if let Ok(selected) = run_fzf_cmd(&directories, None) {
+                    println!("cd {}", selected)
+                }
+            }
+        },
         Some(Commands::Register { pid, number, .. }) => {
             let sys = System::new_with_specifics(
                 RefreshKind::nothing().with_processes(ProcessRefreshKind::everything()),
@@ -88,34 +379,110 @@ fn main() -> std::io::Result<()> {
             let process = sys
                 .process(Pid::from(*pid as usize))
                 .unwrap_or_else(|| panic!("Did not find process with pid {}", pid));
-            let request = ClientRequest {
-                action: Commands::Register {
-                    pid: *pid,
-                    number: *number,
-                    command: process.name().to_string_lossy().into(),
-                },
-                cwd,
-            };
-            let response = jobctl::sessions::send_request(request, Some(true));
-            handle_response(response);
-        }
-        Some(Commands::Run { command }) => {
-            let request = ClientRequest {
-                action: Commands::Run {
-                    command: command.to_string(),
-                },
-                cwd,
-            };
-            let response = jobctl::sessions::send_request(request, Some(true));
-            handle_response(response);
+            let command = process.name().to_string_lossy();
+            let job = exit_on_error(client.register(*pid, *number, &command));
+            print_json(&ServerResponse::Register { job });
         }
-        Some(Commands::Kill) => {
-            let request = ClientRequest {
-                action: Commands::Kill,
-                cwd,
-            };
-            let response = jobctl::sessions::send_request(request, None);
-            handle_response(response);
+        Some(Commands::Run { command, options }) => {
+            let job = exit_on_error(client.run_with(command, *options));
+            print_json(&ServerResponse::Register { job });
+        }
+        Some(Commands::Kill { jobs }) => {
+            exit_on_error(client.kill(*jobs));
+            print_json(&ServerResponse::Kill);
+        }
+        Some(Commands::Gc { dry_run, json }) => {
+            let stale = exit_on_error(client.gc(*dry_run));
+            if *json {
+                print_json(&stale);
+            } else {
+                print_stale(&stale, *dry_run);
+            }
+        }
+        Some(Commands::Signal { job, signal }) => {
+            let job = exit_on_error(client.signal(job, *signal));
+            print_json(&job);
+        }
+        Some(Commands::Freeze { job }) => {
+            let (job, reclaimed) = exit_on_error(client.freeze(job));
+            println!(
+                "Paged out {} from %{} {} {} ({} in total)",
+                format_bytes(reclaimed),
+                job.number,
+                job.pid,
+                job.command,
+                format_bytes(job.reclaimed)
+            );
+        }
+        Some(Commands::Inspect { job, tree, json }) => {
+            let inspection = exit_on_error(client.inspect(job, *tree));
+            if *json {
+                print_json(&inspection);
+            } else {
+                print_inspection(&inspection);
+            }
+        }
+        Some(Commands::WhichPort { port, json }) => {
+            let owners = exit_on_error(client.which_port(*port));
+            if *json {
+                print_json(&owners);
+            } else {
+                print_owners(*port, &owners);
+            }
+            if owners.is_empty() {
+                process::exit(1);
+            }
+        }
+        Some(Commands::Renice { job, nice }) => {
+            let (job, threads) = exit_on_error(client.renice(job, *nice));
+            println!(
+                "Set niceness {} on %{} {} {} ({} threads)",
+                nice, job.number, job.pid, job.command, threads
+            );
+        }
+        Some(Commands::Stop { job }) => {
+            let job = exit_on_error(client.signal(job, libc::SIGSTOP));
+            print_json(&job);
+        }
+        Some(Commands::Cont { job }) => {
+            let job = exit_on_error(client.signal(job, libc::SIGCONT));
+            print_json(&job);
+        }
+        Some(Commands::Term { job }) => {
+            let job = exit_on_error(client.signal(job, libc::SIGTERM));
+            print_json(&job);
+        }
+        Some(Commands::Resume { job, background }) => {
+            if *background {
+                let job = exit_on_error(client.signal(job, libc::SIGCONT));
+                println!("[{}] {} {} &", job.number, job.pid, job.command);
+                for warning in background_warnings(job.pid) {
+                    eprintln!("Warning: {}", warning);
+                }
+                return Ok(());
+            }
+
+            // Signal 0 resolves and validates the job without touching it.
+            let job = exit_on_error(client.signal(job, 0));
+            if job.number == 0 {
+                eprintln!(
+                    "Error: Job {} was started by jobctl, not a shell; use --background",
+                    job.pid
+                );
+                process::exit(2);
+            }
+            let job_tty = terminal_fds(job.pid).into_iter().map(|(_, tty)| tty).next();
+            if let (Some(job_tty), Some(own_tty)) = (job_tty, own_terminal())
+                && job_tty != own_tty
+            {
+                eprintln!(
+                    "Error: Job %{} belongs to {}, not this terminal; use --background",
+                    job.number,
+                    job_tty.display()
+                );
+                process::exit(2);
+            }
+            println!("fg %{}", job.number);
         }
         Some(Commands::Init { shell }) => {
             // TODO: add bash, fish support
@@ -126,7 +493,84 @@ fn main() -> std::io::Result<()> {
             // This needs to print for the shell to evaluate
             println!("{}", output);
         }
-        None => {}
+        Some(Commands::Session { command }) => match command {
+            SessionCommands::Kill { dir, grace, json } => {
+                let dir = match dir {
+                    Some(dir) => fs::canonicalize(dir).unwrap_or_else(|_| client.cwd().join(dir)),
+                    None => client.cwd().to_path_buf(),
+                };
+                let killed = exit_on_error(client.kill_session(&dir, *grace));
+
+                if *json {
+                    print_json(&killed);
+                } else {
+                    print_killed(&dir, &killed);
+                }
+                if killed
+                    .iter()
+                    .any(|k| matches!(k.outcome, KillOutcome::Survived | KillOutcome::Failed))
+                {
+                    process::exit(1);
+                }
+            }
+        },
+        Some(Commands::Server { command }) => match command {
+            ServerCommands::Status { json } => {
+                let status = exit_on_error(client.status());
+
+                if *json {
+                    print_json(&status);
+                } else {
+                    print_status(&status);
+                }
+            }
+            ServerCommands::Upgrade { binary } => {
+                let binary = match binary {
+                    Some(binary) => binary.clone(),
+                    None => env::current_exe()?.with_file_name("job-server"),
+                };
+                let binary = fs::canonicalize(&binary).unwrap_or(binary);
+                let pid = exit_on_error(client.upgrade(&binary));
+                println!("Server {} is now running {}", pid, binary.display());
+            }
+            ServerCommands::Metrics => {
+                print!("{}", exit_on_error(client.metrics()));
+            }
+        },
+        Some(Commands::Schema { name }) => match name {
+            Some(name) => match schema(name) {
+                Some(schema) => print_json(&schema),
+                None => {
+                    eprintln!(
+                        "Error: Unknown type `{}`, expected one of: {}",
+                        name,
+                        SCHEMA_TYPES.join(", ")
+                    );
+                    process::exit(2);
+                }
+            },
+            None => {
+                let schemas: serde_json::Map<String, serde_json::Value> = SCHEMA_TYPES
+                    .iter()
+                    .map(|name| (name.to_string(), schema(name).unwrap().to_value()))
+                    .collect();
+                print_json(&schemas);
+            }
+        },
+        Some(Commands::InstallService { force }) => {
+            if let Err(e) = install_service(*force) {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+        // Protocol-only actions cannot be given on the command line
+        Some(
+            Commands::Status
+            | Commands::Upgrade { .. }
+            | Commands::Metrics
+            | Commands::KillSession { .. },
+        )
+        | None => {}
     }
 
     Ok(())
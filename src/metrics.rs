@@ -0,0 +1,287 @@
+//! Prometheus metrics for job-server, in the text exposition format.
+//!
+//! Request and exit counters are updated as requests are dispatched; the
+//! session and job gauges are computed from the store when rendered.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sysinfo::{Pid, ProcessRefreshKind, ProcessStatus, ProcessesToUpdate, System};
+
+use crate::sessions::{ServerStatus, Session};
+use crate::utils::{lock, signal_name};
+
+/// Upper bounds of the request latency buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Upper bounds of the job age buckets, in seconds.
+const AGE_BUCKETS: &[f64] = &[60.0, 300.0, 1800.0, 3600.0, 21600.0, 86400.0, 604800.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    run_exits: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    /// Count one dispatched request and how long it took.
+    pub fn record_request(&self, action: &'static str, elapsed: Duration, failed: bool) {
+        let result = if failed { "error" } else { "ok" };
+        *lock(&self.requests).entry((action, result)).or_default() += 1;
+
+        lock(&self.latency)
+            .entry(action)
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count how a job started with `run` ended: its exit code, or the
+    /// signal that killed it.
+    pub fn record_exit(&self, status: &ExitStatus) {
+        let label = match (status.code(), status.signal()) {
+            (Some(code), _) => code.to_string(),
+            (None, Some(signal)) => signal_name(signal),
+            (None, None) => "unknown".to_string(),
+        };
+        *lock(&self.run_exits).entry(label).or_default() += 1;
+    }
+
+    pub fn render(&self, sessions: &[Session], status: &ServerStatus) -> String {
+        let mut out = String::new();
+
+        gauge(
+            &mut out,
+            "jobctl_sessions",
+            "Directories with tracked jobs",
+            sessions.len(),
+        );
+        gauge(
+            &mut out,
+            "jobctl_connections",
+            "Open client connections",
+            status.connections,
+        );
+        gauge(
+            &mut out,
+            "jobctl_subscribers",
+            "Open event subscriptions",
+            status.subscribers,
+        );
+        gauge(
+            &mut out,
+            "jobctl_uptime_seconds",
+            "Seconds since the server started",
+            status.uptime,
+        );
+
+        let pids: Vec<Pid> = sessions
+            .iter()
+            .flat_map(|s| s.jobs.iter())
+            .map(|job| Pid::from(job.pid as usize))
+            .collect();
+        let mut sys = System::new();
+        sys.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&pids),
+            true,
+            ProcessRefreshKind::nothing(),
+        );
+
+        let mut states: BTreeMap<&str, usize> =
+            [("exited", 0), ("running", 0), ("stopped", 0)].into();
+        let mut ages = Histogram::new(AGE_BUCKETS);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        for job in sessions.iter().flat_map(|s| s.jobs.iter()) {
+            let state = match sys.process(Pid::from(job.pid as usize)).map(|p| p.status()) {
+                Some(ProcessStatus::Stop) => "stopped",
+                Some(ProcessStatus::Zombie | ProcessStatus::Dead) | None => "exited",
+                Some(_) => "running",
+            };
+            *states.entry(state).or_default() += 1;
+            ages.observe(now.saturating_sub(job.suspended) as f64);
+        }
+
+        header(
+            &mut out,
+            "jobctl_jobs",
+            "gauge",
+            "Tracked jobs by process state",
+        );
+        for (state, count) in &states {
+            let _ = writeln!(out, "jobctl_jobs{{state=\"{}\"}} {}", state, count);
+        }
+
+        header(
+            &mut out,
+            "jobctl_job_age_seconds",
+            "histogram",
+            "Time since each tracked job was suspended or started",
+        );
+        ages.write(&mut out, "jobctl_job_age_seconds", "");
+
+        header(
+            &mut out,
+            "jobctl_requests_total",
+            "counter",
+            "Requests handled, by action and result",
+        );
+        for ((action, result), count) in lock(&self.requests).iter() {
+            let _ = writeln!(
+                out,
+                "jobctl_requests_total{{action=\"{}\",result=\"{}\"}} {}",
+                action, result, count
+            );
+        }
+
+        header(
+            &mut out,
+            "jobctl_request_duration_seconds",
+            "histogram",
+            "Time spent handling requests, by action",
+        );
+        for (action, histogram) in lock(&self.latency).iter() {
+            histogram.write(
+                &mut out,
+                "jobctl_request_duration_seconds",
+                &format!("action=\"{}\",", action),
+            );
+        }
+
+        header(
+            &mut out,
+            "jobctl_run_exits_total",
+            "counter",
+            "Jobs started with `run` that have ended, by exit code or signal",
+        );
+        for (status, count) in lock(&self.run_exits).iter() {
+            let _ = writeln!(
+                out,
+                "jobctl_run_exits_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        out
+    }
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Write the cumulative buckets, sum and count. `labels` is either empty
+    /// or a list of `name="value",` pairs to put before `le`.
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_cumulative_buckets() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        for value in [0.05, 0.5, 0.5, 3.0] {
+            histogram.observe(value);
+        }
+
+        let mut out = String::new();
+        histogram.write(&mut out, "jobctl_latency_seconds", "");
+        assert_eq!(
+            out,
+            "jobctl_latency_seconds_bucket{le=\"0.1\"} 1\n\
+             jobctl_latency_seconds_bucket{le=\"1\"} 3\n\
+             jobctl_latency_seconds_bucket{le=\"+Inf\"} 4\n\
+             jobctl_latency_seconds_sum 4.05\n\
+             jobctl_latency_seconds_count 4\n"
+        );
+    }
+
+    #[test]
+    fn puts_labels_before_le() {
+        let mut histogram = Histogram::new(&[60.0]);
+        histogram.observe(60.0);
+
+        let mut out = String::new();
+        histogram.write(&mut out, "jobctl_job_age_seconds", "state=\"running\",");
+        assert_eq!(
+            out,
+            "jobctl_job_age_seconds_bucket{state=\"running\",le=\"60\"} 1\n\
+             jobctl_job_age_seconds_bucket{state=\"running\",le=\"+Inf\"} 1\n\
+             jobctl_job_age_seconds_sum{state=\"running\"} 60\n\
+             jobctl_job_age_seconds_count{state=\"running\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn writes_empty_histograms() {
+        let mut out = String::new();
+        Histogram::new(LATENCY_BUCKETS).write(&mut out, "h", "");
+        assert_eq!(out.lines().count(), LATENCY_BUCKETS.len() + 3);
+        assert!(out.contains("h_bucket{le=\"0.001\"} 0\n"));
+        assert!(out.ends_with("h_sum 0\nh_count 0\n"));
+    }
+}
@@ -0,0 +1,324 @@
+//! Stale job policies, read from `policies.json` in the config directory.
+//!
+//! The file holds a list of rules such as
+//!
+//! ```json
+//! [
+//!   { "after": "1d", "action": "warn" },
+//!   { "command": "less", "after": "7d", "action": "kill" },
+//!   { "command": "cargo*", "after": "2h", "action": "freeze" },
+//!   { "directory": "/home/me/scratch", "command": "python*", "after": "12h", "action": "term" }
+//! ]
+//! ```
+//!
+//! A rule applies to a stopped job once it has been suspended for `after`,
+//! if its session is `directory` or below it and its command matches the
+//! `command` glob. When several rules apply the strongest action wins.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use directories::ProjectDirs;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::sessions::{Job, JobState, Session, StaleJob};
+use crate::utils::parse_duration;
+
+/// How often the server applies the policies.
+pub const POLICY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What to do with a job a policy applies to, weakest first.
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    /// Log it and print a notice on the job's terminal, once.
+    Warn,
+    /// Page out its memory to swap, once per suspension. Needs CAP_SYS_NICE,
+    /// and is skipped until job-server restarts once that is found missing.
+    Freeze,
+    /// Send SIGCONT and SIGTERM to its process group.
+    Term,
+    /// Send SIGKILL to its process group.
+    Kill,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// Only jobs in this directory or below it.
+    pub directory: Option<PathBuf>,
+    /// Only jobs whose command matches this glob, where `*` matches anything.
+    pub command: Option<String>,
+    /// How long the job must have been suspended, e.g. `7d`.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub after: Duration,
+    pub action: PolicyAction,
+}
+
+impl Policy {
+    fn applies(&self, directory: &Path, job: &Job, suspended_for: Duration) -> bool {
+        suspended_for >= self.after
+            && self
+                .directory
+                .as_ref()
+                .is_none_or(|dir| directory.starts_with(dir))
+            && self
+                .command
+                .as_ref()
+                .is_none_or(|pattern| glob_match(pattern, &job.command))
+    }
+}
+
+pub fn policies_file() -> io::Result<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "jobctl")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No home directory"))?;
+    Ok(dirs.config_dir().join("policies.json"))
+}
+
+/// Read the policies in `path`. A missing file means no policies.
+pub fn load_policies(path: &Path) -> Result<Vec<Policy>, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid policies in {}: {}", path.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Every stopped job in `sessions` that a policy applies to at `now`
+/// (seconds since the epoch), with the strongest action that applies.
+pub fn stale_jobs(policies: &[Policy], sessions: &[Session], now: u64) -> Vec<StaleJob> {
+    sessions
+        .iter()
+        .flat_map(|session| session.jobs.iter().map(move |job| (session, job)))
+        .filter(|(_, job)| job.state == JobState::Stopped)
+        .filter_map(|(session, job)| {
+            let suspended_for = Duration::from_secs(now.saturating_sub(job.suspended));
+            let action = policies
+                .iter()
+                .filter(|policy| policy.applies(&session.directory, job, suspended_for))
+                .map(|policy| policy.action)
+                .max()?;
+
+            Some(StaleJob {
+                directory: session.directory.clone(),
+                job: job.clone(),
+                action,
+                suspended_for: suspended_for.as_secs(),
+            })
+        })
+        .collect()
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of characters
+/// and everything else matches itself.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Seconds(u64),
+        Text(String),
+    }
+
+    match Raw::deserialize(deserializer)? {
+        Raw::Seconds(secs) => Ok(Duration::from_secs(secs)),
+        Raw::Text(text) => parse_duration(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::unix_time;
+
+    const DAY: u64 = 86400;
+    const NOW: u64 = 100 * DAY;
+
+    fn policies(json: &str) -> Vec<Policy> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn job(pid: u32, command: &str, state: JobState, suspended: u64) -> Job {
+        Job {
+            pid,
+            command: command.to_string(),
+            number: 1,
+            suspended,
+            state,
+            start_time: 0,
+            frozen: 0,
+            reclaimed: 0,
+            history: vec![],
+        }
+    }
+
+    fn session(directory: &str, jobs: Vec<Job>) -> Session {
+        Session {
+            jobs,
+            directory: PathBuf::from(directory),
+            usage: None,
+        }
+    }
+
+    fn actions(stale: &[StaleJob]) -> Vec<(u32, PolicyAction)> {
+        stale.iter().map(|s| (s.job.pid, s.action)).collect()
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("less", "less"));
+        assert!(!glob_match("less", "less notes"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("cargo*", "cargo build"));
+        assert!(!glob_match("cargo*", "xcargo"));
+        assert!(glob_match("*.md", "vim notes.md"));
+        assert!(!glob_match("*.md", "vim notes.mdx"));
+        assert!(glob_match("python*server*", "python -m http.server 8000"));
+        assert!(!glob_match("python*server*", "python -m http"));
+        // `*` must not let the prefix and suffix overlap.
+        assert!(!glob_match("ab*ba", "aba"));
+        assert!(glob_match("ab*ba", "abba"));
+    }
+
+    #[test]
+    fn applies_rules_once_suspended_long_enough() {
+        let policies = policies(r#"[{ "after": "1d", "action": "warn" }]"#);
+        let sessions = vec![session(
+            "/work",
+            vec![
+                job(1, "vim", JobState::Stopped, NOW - 2 * DAY),
+                job(2, "less", JobState::Stopped, NOW - 3600),
+                job(3, "make", JobState::Running, NOW - 5 * DAY),
+            ],
+        )];
+
+        let stale = stale_jobs(&policies, &sessions, NOW);
+        assert_eq!(actions(&stale), [(1, PolicyAction::Warn)]);
+        assert_eq!(stale[0].suspended_for, 2 * DAY);
+        assert_eq!(stale[0].directory, PathBuf::from("/work"));
+    }
+
+    #[test]
+    fn filters_by_directory_and_command() {
+        let policies = policies(
+            r#"[
+                { "directory": "/work/scratch", "after": 0, "action": "term" },
+                { "command": "cargo*", "after": 0, "action": "freeze" }
+            ]"#,
+        );
+        let sessions = vec![
+            session("/work", vec![job(1, "vim", JobState::Stopped, NOW)]),
+            session(
+                "/work/scratch/a",
+                vec![job(2, "vim", JobState::Stopped, NOW)],
+            ),
+            session(
+                "/work/scratchpad",
+                vec![job(3, "vim", JobState::Stopped, NOW)],
+            ),
+            session("/other", vec![job(4, "cargo test", JobState::Stopped, NOW)]),
+        ];
+
+        let stale = stale_jobs(&policies, &sessions, NOW);
+        assert_eq!(
+            actions(&stale),
+            [(2, PolicyAction::Term), (4, PolicyAction::Freeze)]
+        );
+    }
+
+    #[test]
+    fn strongest_action_wins() {
+        let policies = policies(
+            r#"[
+                { "after": "1h", "action": "kill" },
+                { "after": "1m", "action": "warn" },
+                { "command": "vim*", "after": "1m", "action": "freeze" }
+            ]"#,
+        );
+        let sessions = vec![session(
+            "/work",
+            vec![
+                job(1, "vim a", JobState::Stopped, NOW - 120),
+                job(2, "less b", JobState::Stopped, NOW - 120),
+                job(3, "vim c", JobState::Stopped, NOW - 7200),
+            ],
+        )];
+
+        assert_eq!(
+            actions(&stale_jobs(&policies, &sessions, NOW)),
+            [
+                (1, PolicyAction::Freeze),
+                (2, PolicyAction::Warn),
+                (3, PolicyAction::Kill)
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_from_when_a_job_was_last_stopped() {
+        let policies = policies(r#"[{ "after": "1d", "action": "kill" }]"#);
+        let now = unix_time();
+
+        // Started two days ago with `run`, paused just now.
+        let mut paused = job(1, "make", JobState::Running, now - 2 * DAY);
+        paused.set_state(JobState::Stopped);
+        assert!(paused.suspended >= now);
+        let sessions = vec![session("/work", vec![paused])];
+
+        assert!(stale_jobs(&policies, &sessions, now).is_empty());
+    }
+
+    #[test]
+    fn ignores_future_suspensions() {
+        let policies = policies(r#"[{ "after": 0, "action": "warn" }]"#);
+        let sessions = vec![session(
+            "/work",
+            vec![job(1, "vim", JobState::Stopped, NOW + DAY)],
+        )];
+
+        let stale = stale_jobs(&policies, &sessions, NOW);
+        assert_eq!(stale[0].suspended_for, 0);
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        assert!(serde_json::from_str::<Vec<Policy>>(r#"[{ "after": "1d" }]"#).is_err());
+        assert!(
+            serde_json::from_str::<Vec<Policy>>(r#"[{ "after": "1y", "action": "warn" }]"#)
+                .is_err()
+        );
+        assert!(
+            serde_json::from_str::<Vec<Policy>>(
+                r#"[{ "after": "1d", "action": "warn", "name": "x" }]"#
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::{self, BufRead, Read, Write};
+use std::time::Duration;
+
+use crate::sessions::ClientRequest;
+
+/// Largest request or response line accepted on the socket, in bytes.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// How long the server waits on a silent or stalled client before dropping it.
+pub const SERVER_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the client waits for the server to answer a request.
+pub const CLIENT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Read one newline-terminated message of at most [`MAX_MESSAGE_SIZE`] bytes.
+///
+/// Returns `None` if the peer closed the connection without sending anything.
+/// A final message without a trailing newline is accepted at EOF.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    reader
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut buf)?;
+
+    check_message(buf)
+}
+
+/// Async counterpart of [`read_message`].
+#[cfg(feature = "async")]
+pub async fn read_message_async<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    let mut buf = Vec::new();
+    reader
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut buf)
+        .await?;
+
+    check_message(buf)
+}
+
+fn check_message(mut buf: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    if buf.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message exceeds {} bytes", MAX_MESSAGE_SIZE),
+        ));
+    }
+
+    Ok(Some(buf))
+}
+
+/// Write `payload` as a single newline-terminated message.
+pub fn write_message<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(payload)?;
+    writer.write_all(b"\n")?;
+    writer.flush()
+}
+
+/// Async counterpart of [`write_message`].
+#[cfg(feature = "async")]
+pub async fn write_message_async<W>(writer: &mut W, payload: &[u8]) -> io::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let mut line = Vec::with_capacity(payload.len() + 1);
+    line.extend_from_slice(payload);
+    line.push(b'\n');
+
+    writer.write_all(&line).await?;
+    writer.flush().await
+}
+
+/// Decode a request message. This is the entry point for the fuzz target, so
+/// it must never panic on arbitrary input.
+pub fn parse_request(message: &[u8]) -> Result<ClientRequest, String> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(format!(
+            "Request Error: message exceeds {} bytes",
+            MAX_MESSAGE_SIZE
+        ));
+    }
+
+    serde_json::from_slice::<ClientRequest>(message).map_err(|e| format!("Request Error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read_all(input: &[u8]) -> Vec<io::Result<Option<Vec<u8>>>> {
+        let mut reader = Cursor::new(input.to_vec());
+        let mut messages = vec![];
+        loop {
+            let message = read_message(&mut reader);
+            let done = !matches!(message, Ok(Some(_)));
+            messages.push(message);
+            if done {
+                return messages;
+            }
+        }
+    }
+
+    #[test]
+    fn reads_newline_terminated_messages() {
+        let messages: Vec<_> = read_all(b"{\"a\":1}\n{\"b\":2}\n")
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                Some(b"{\"a\":1}".to_vec()),
+                Some(b"{\"b\":2}".to_vec()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_a_final_message_without_newline() {
+        let mut reader = Cursor::new(b"last".to_vec());
+        assert_eq!(read_message(&mut reader).unwrap(), Some(b"last".to_vec()));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn returns_none_at_eof() {
+        assert_eq!(read_message(&mut Cursor::new(vec![])).unwrap(), None);
+    }
+
+    #[test]
+    fn keeps_empty_lines() {
+        let mut reader = Cursor::new(b"\nnext\n".to_vec());
+        assert_eq!(read_message(&mut reader).unwrap(), Some(vec![]));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(b"next".to_vec()));
+    }
+
+    #[test]
+    fn accepts_messages_up_to_the_limit() {
+        let mut input = vec![b'x'; MAX_MESSAGE_SIZE];
+        input.push(b'\n');
+        let message = read_message(&mut Cursor::new(input)).unwrap().unwrap();
+        assert_eq!(message.len(), MAX_MESSAGE_SIZE);
+
+        // At EOF there is no newline to strip.
+        let input = vec![b'x'; MAX_MESSAGE_SIZE];
+        let message = read_message(&mut Cursor::new(input)).unwrap().unwrap();
+        assert_eq!(message.len(), MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        for len in [MAX_MESSAGE_SIZE + 1, MAX_MESSAGE_SIZE * 2] {
+            let mut input = vec![b'x'; len];
+            input.push(b'\n');
+            let error = read_message(&mut Cursor::new(input)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn stops_reading_at_the_limit() {
+        // An endless line must not be buffered in full.
+        let mut reader = io::BufReader::new(io::repeat(b'x'));
+        let error = read_message(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn writes_one_line_per_message() {
+        let mut output = vec![];
+        write_message(&mut output, b"{\"action\":\"status\"}").unwrap();
+        write_message(&mut output, b"").unwrap();
+        assert_eq!(output, b"{\"action\":\"status\"}\n\n");
+
+        let mut reader = Cursor::new(output);
+        assert_eq!(
+            read_message(&mut reader).unwrap(),
+            Some(b"{\"action\":\"status\"}".to_vec())
+        );
+    }
+
+    #[test]
+    fn limits_parsed_requests() {
+        assert!(parse_request(br#"{"action":"status","cwd":"/"}"#).is_ok());
+        assert!(parse_request(b"not json").is_err());
+        assert!(parse_request(&vec![b' '; MAX_MESSAGE_SIZE + 1]).is_err());
+    }
+}
@@ -0,0 +1,1810 @@
+use std::collections::HashSet;
+use std::io::{self, BufReader};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::Command as ProcessCommand;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+use crate::cli::{Commands, JobsPolicy, RunOptions};
+use crate::jsonrpc;
+use crate::metrics::Metrics;
+use crate::policy::{POLICY_INTERVAL, PolicyAction, load_policies, policies_file, stale_jobs};
+use crate::protocol::{SERVER_IO_TIMEOUT, parse_request, read_message, write_message};
+#[cfg(feature = "async")]
+use crate::protocol::{read_message_async, write_message_async};
+use crate::sessions::{
+    ClientRequest, ErrorCode, Inspection, Job, JobOutput, JobState, KillOutcome, KilledJob,
+    PortOwner, ResourceUsage, ServerResponse, ServerStatus, Session, StaleJob, cleanup_sessions,
+    save_sessions, select_job,
+};
+use crate::snapshot::{Snapshot, command_line};
+use crate::sockets::{SocketTable, listening_ports};
+use crate::utils::{
+    ProcStat, apply_run_options, format_bytes, format_duration, is_job_suspended, lock,
+    notify_terminal, open_files, page_out, proc_stat, process_group, raises_priority, renice,
+    signal_job, signal_name, terminal_fds, time_ago, try_reap,
+};
+
+/// How long a JSON-RPC connection may sit idle between calls.
+pub const JSONRPC_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Shared in-memory job store, one entry per directory.
+pub type Store = Arc<Mutex<Vec<Session>>>;
+
+/// Run a request, turning a panic in the handler into an `Internal` error
+/// response instead of a dropped connection.
+pub fn dispatch(req: ClientRequest, server: &Server) -> ServerResponse {
+    let action = req.action.name();
+    let started = Instant::now();
+
+    let response = catch_panic(|| handle_request(req, server));
+
+    let failed = matches!(response, ServerResponse::Error { .. });
+    server
+        .metrics
+        .record_request(action, started.elapsed(), failed);
+    response
+}
+
+/// Call `handler`, turning a panic into an `Internal` error response.
+fn catch_panic(handler: impl FnOnce() -> ServerResponse) -> ServerResponse {
+    match panic::catch_unwind(AssertUnwindSafe(handler)) {
+        Ok(response) => response,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "request handler panicked".to_string());
+            error!("Request handler panicked: {}", message);
+            ServerResponse::error(ErrorCode::Internal, message)
+        }
+    }
+}
+
+/// Run a single request against the server state.
+///
+/// This is transport-agnostic and shared by the blocking and async servers.
+pub fn handle_request(req: ClientRequest, server: &Server) -> ServerResponse {
+    info!("Processing action: {:?}", req.action);
+    let store = &server.store;
+
+    match req.action {
+        Commands::List { dir, .. } => {
+            let mut sessions = cleanup_sessions(store);
+            let snapshot = Snapshot::take();
+            let sockets = SocketTable::read();
+
+            match dir {
+                Some(directory) => {
+                    let directory = PathBuf::from(directory);
+                    let Some(session) = sessions.iter().find(|s| s.directory == directory) else {
+                        return ServerResponse::error(
+                            ErrorCode::NotFound,
+                            format!("No jobs found for directory {}", directory.display()),
+                        );
+                    };
+                    let jobs = session
+                        .jobs
+                        .clone()
+                        .iter()
+                        .map(|job| JobOutput {
+                            pid: job.pid,
+                            command: job.command.clone(),
+                            number: job.number,
+                            suspended: time_ago(job.suspended),
+                            state: job.state,
+                            usage: snapshot.usage(job.pid).unwrap_or_default(),
+                            ports: listening_ports(&sockets.held_by(&job_pids(&snapshot, job))),
+                        })
+                        .collect();
+                    ServerResponse::ListJobs { jobs }
+                }
+                _ => {
+                    for session in &mut sessions {
+                        let mut total = ResourceUsage::default();
+                        for job in &session.jobs {
+                            total += snapshot.usage(job.pid).unwrap_or_default();
+                        }
+                        session.usage = Some(total);
+                    }
+                    ServerResponse::ListSessions { sessions }
+                }
+            }
+        }
+        Commands::Register {
+            pid,
+            number,
+            command,
+        } => {
+            let job = Job::new(pid, number, command, JobState::Stopped);
+
+            info!("Creating new job: {:?}", job);
+
+            let mut sessions = lock(store);
+            if let Some(session) = sessions.iter_mut().find(|s| s.directory == req.cwd) {
+                if let Some(existing) = session.jobs.iter_mut().find(|j| j.pid == job.pid) {
+                    // Stopped again after being continued in the background,
+                    // so whatever was paged out may be resident again.
+                    existing.set_state(JobState::Stopped);
+                    // The shell may have brought it to the foreground without
+                    // jobctl noticing, so this is a new suspension either way.
+                    existing.suspended = job.suspended;
+                    existing.frozen = 0;
+                    info!(
+                        "Job with PID {} already exists in session: {:?}",
+                        job.pid, session
+                    );
+                } else {
+                    session.jobs.push(job.clone());
+                    info!("Adding to job to session: {:?}", session);
+                }
+            } else {
+                let session = Session {
+                    jobs: vec![job.clone()],
+                    directory: req.cwd,
+                    usage: None,
+                };
+
+                info!("No session found, creating session: {:?}", session);
+
+                sessions.push(session);
+            }
+
+            ServerResponse::Register { job }
+        }
+        Commands::Kill { jobs } => {
+            server.request_shutdown(Shutdown::Kill(jobs));
+            ServerResponse::Kill
+        }
+        Commands::Upgrade { binary } => {
+            if !is_executable(&binary) {
+                return ServerResponse::error(
+                    ErrorCode::InvalidRequest,
+                    format!("{} is not an executable file", binary.display()),
+                );
+            }
+
+            server.request_shutdown(Shutdown::Upgrade(binary));
+            ServerResponse::Upgrade {
+                pid: std::process::id(),
+            }
+        }
+        Commands::Run { command, options } => {
+            if let Err(message) = options.validate() {
+                return ServerResponse::error(ErrorCode::InvalidRequest, message);
+            }
+
+            // Spawn the command as a background process
+            // In its own process group so it can be signalled like a shell job
+            let mut process = ProcessCommand::new("sh");
+            process
+                .arg("-c")
+                .arg(&command)
+                .current_dir(&req.cwd)
+                .process_group(0);
+            if options != RunOptions::default() {
+                unsafe { process.pre_exec(move || apply_run_options(&options)) };
+            }
+
+            let mut child = match process.spawn() {
+                Ok(child) => child,
+                // The priority is set in the child, so a refusal shows up as
+                // the spawn failing.
+                Err(e)
+                    if e.kind() == io::ErrorKind::PermissionDenied && raises_priority(&options) =>
+                {
+                    return ServerResponse::error(
+                        ErrorCode::InvalidRequest,
+                        format!(
+                            "Not allowed to start `{}` at a higher priority; raising priority needs CAP_SYS_NICE",
+                            command
+                        ),
+                    );
+                }
+                Err(e) => {
+                    return ServerResponse::error(
+                        ErrorCode::SpawnFailed,
+                        format!("Failed to spawn process: {}", e),
+                    );
+                }
+            };
+
+            let pid = child.id();
+
+            // Reap the job when it exits and count how it ended.
+            let metrics = Arc::clone(&server.metrics);
+            let waiter = thread::Builder::new()
+                .name(format!("run-{}", pid))
+                .spawn(move || match child.wait() {
+                    Ok(status) => {
+                        info!("Job {} exited: {}", pid, status);
+                        metrics.record_exit(&status);
+                    }
+                    Err(e) => warn!("Failed to wait for job {}: {}", pid, e),
+                });
+            if let Err(e) = waiter {
+                warn!("Failed to spawn waiter for job {}: {}", pid, e);
+            }
+
+            let job = Job::new(pid, 0, command.clone(), JobState::Running);
+
+            info!("Spawning new job: {:?}", job);
+
+            let mut sessions = lock(store);
+            if let Some(session) = sessions.iter_mut().find(|s| s.directory == req.cwd) {
+                if session.jobs.iter().all(|j| j.pid != job.pid) {
+                    session.jobs.push(job.clone());
+                    info!("Adding spawned job to session: {:?}", session);
+                } else {
+                    info!(
+                        "Job with PID {} already exists in session: {:?}",
+                        job.pid, session
+                    );
+                }
+            } else {
+                let session = Session {
+                    jobs: vec![job.clone()],
+                    directory: req.cwd,
+                    usage: None,
+                };
+
+                info!(
+                    "No session found, creating session for spawned job: {:?}",
+                    session
+                );
+
+                sessions.push(session);
+            }
+
+            ServerResponse::Register { job }
+        }
+        Commands::Gc { dry_run, .. } => match server.collect_garbage(dry_run) {
+            Ok(jobs) => ServerResponse::Gc { jobs },
+            Err(message) => ServerResponse::error(ErrorCode::InvalidRequest, message),
+        },
+        Commands::Signal { job, signal } => signal_tracked_job(store, &req.cwd, &job, signal),
+        Commands::Freeze { job } => freeze_tracked_job(store, &req.cwd, &job),
+        Commands::Inspect { job, tree, .. } => inspect_tracked_job(store, &req.cwd, &job, tree),
+        Commands::WhichPort { port, .. } => which_port(store, port),
+        Commands::Renice { job, nice } => renice_tracked_job(store, &req.cwd, &job, nice),
+        Commands::KillSession { dir, grace_ms } => kill_session(store, &dir, kill_grace(grace_ms)),
+        Commands::Status => ServerResponse::Status {
+            status: server.status(),
+        },
+        Commands::Metrics => ServerResponse::Metrics {
+            text: server.metrics_text(),
+        },
+        Commands::Init { .. }
+        | Commands::Server { .. }
+        | Commands::Session { .. }
+        | Commands::Schema { .. }
+        | Commands::Stop { .. }
+        | Commands::Cont { .. }
+        | Commands::Term { .. }
+        | Commands::Resume { .. }
+        | Commands::InstallService { .. } => ServerResponse::error(
+            ErrorCode::Unsupported,
+            "This action is handled by the jobctl client, not the server",
+        ),
+    }
+}
+
+/// Send `signal` to the process group of the job in `cwd` that `selector`
+/// refers to, after checking its PID has not been reused.
+fn signal_tracked_job(store: &Store, cwd: &Path, selector: &str, signal: i32) -> ServerResponse {
+    let mut sessions = lock(store);
+    let (job, stat) = match find_tracked_job(&mut sessions, cwd, selector) {
+        Ok(found) => found,
+        Err((code, message)) => return ServerResponse::error(code, message),
+    };
+
+    // Like a shell's `kill %N`, continue a stopped job so it can act on the
+    // signal instead of leaving it pending.
+    let resume = stat.is_stopped()
+        && !matches!(
+            signal,
+            0 | libc::SIGSTOP
+                | libc::SIGTSTP
+                | libc::SIGTTIN
+                | libc::SIGTTOU
+                | libc::SIGCONT
+                | libc::SIGKILL
+        );
+
+    info!("Sending {} to job {:?}", signal_name(signal), job);
+    let sent = signal_job(job.pid, signal).and_then(|()| match resume {
+        true => signal_job(job.pid, libc::SIGCONT),
+        false => Ok(()),
+    });
+    if let Err(e) = sent {
+        return ServerResponse::error(
+            ErrorCode::Internal,
+            format!("Failed to signal PID {}: {}", job.pid, e),
+        );
+    }
+
+    match signal {
+        libc::SIGSTOP => job.set_state(JobState::Stopped),
+        libc::SIGCONT => job.set_state(JobState::Running),
+        _ if resume => job.set_state(JobState::Running),
+        _ => {}
+    }
+    if job.state == JobState::Running {
+        job.frozen = 0;
+    }
+
+    ServerResponse::Signal {
+        job: job.clone(),
+        signal,
+    }
+}
+
+/// The job in `cwd` that `selector` refers to and its process. A job whose
+/// process has exited or whose PID has been reused is forgotten.
+fn find_tracked_job<'a>(
+    sessions: &'a mut [Session],
+    cwd: &Path,
+    selector: &str,
+) -> Result<(&'a mut Job, ProcStat), (ErrorCode, String)> {
+    let Some(session) = sessions.iter_mut().find(|s| s.directory == cwd) else {
+        return Err((
+            ErrorCode::NotFound,
+            format!("No jobs found for directory {}", cwd.display()),
+        ));
+    };
+    let index = select_job(&session.jobs, selector)?;
+
+    let job = &session.jobs[index];
+    let Some(stat) = job.process() else {
+        let message = format!("Job %{} (PID {}) is no longer running", job.number, job.pid);
+        session.jobs.remove(index);
+        return Err((ErrorCode::NotFound, message));
+    };
+
+    Ok((&mut session.jobs[index], stat))
+}
+
+/// Describe the job in `cwd` that `selector` refers to.
+fn inspect_tracked_job(store: &Store, cwd: &Path, selector: &str, tree: bool) -> ServerResponse {
+    let (job, stat) = match find_tracked_job(&mut lock(store), cwd, selector) {
+        Ok((job, stat)) => (job.clone(), stat),
+        Err((code, message)) => return ServerResponse::error(code, message),
+    };
+
+    let snapshot = Snapshot::take();
+    let process = snapshot.process(job.pid);
+    let sockets = SocketTable::read().held_by(&job_pids(&snapshot, &job));
+    let open_files = open_files(job.pid).map(|leader| {
+        let descendants = snapshot.descendants(job.pid).into_iter();
+        leader + descendants.filter_map(open_files).sum::<u64>()
+    });
+
+    ServerResponse::Inspect {
+        inspection: Box::new(Inspection {
+            directory: cwd.to_path_buf(),
+            pgid: stat.pgrp,
+            sid: stat.session,
+            shell_pid: stat.ppid,
+            shell: snapshot.process(stat.ppid).map(command_line),
+            tty: terminal_fds(job.pid).into_iter().next().map(|(_, tty)| tty),
+            cmdline: process.map(command_line),
+            exe: process.and_then(|p| p.exe()).map(Path::to_path_buf),
+            cwd: process.and_then(|p| p.cwd()).map(Path::to_path_buf),
+            started: process.map(|p| p.start_time()),
+            usage: snapshot.usage(job.pid).unwrap_or_default(),
+            open_files,
+            sockets,
+            tree: tree.then(|| snapshot.tree(job.pid)),
+            job,
+        }),
+    }
+}
+
+/// Set the niceness of every process in the job in `cwd` that `selector`
+/// refers to: its process group and its descendants.
+fn renice_tracked_job(store: &Store, cwd: &Path, selector: &str, nice: i32) -> ServerResponse {
+    if !(-20..=19).contains(&nice) {
+        return ServerResponse::error(
+            ErrorCode::InvalidRequest,
+            format!("nice must be between -20 and 19, not {}", nice),
+        );
+    }
+    let job = match find_tracked_job(&mut lock(store), cwd, selector) {
+        Ok((job, _)) => job.clone(),
+        Err((code, message)) => return ServerResponse::error(code, message),
+    };
+
+    let mut pids = job_pids(&Snapshot::take(), &job);
+    pids.extend(process_group(job.pid));
+    pids.sort();
+    pids.dedup();
+
+    let mut threads = 0;
+    for pid in pids {
+        match renice(pid, nice) {
+            Ok(changed) => threads += changed as u32,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) if e.raw_os_error() == Some(libc::EACCES) => {
+                return ServerResponse::error(
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "Not allowed to set niceness {} on PID {}; raising priority needs CAP_SYS_NICE",
+                        nice, pid
+                    ),
+                );
+            }
+            Err(e) => {
+                return ServerResponse::error(
+                    ErrorCode::Internal,
+                    format!("Failed to renice PID {}: {}", pid, e),
+                );
+            }
+        }
+    }
+    info!(
+        "Set niceness {} on {} threads of job {}",
+        nice, threads, job.pid
+    );
+
+    ServerResponse::Renice { job, nice, threads }
+}
+
+/// The job leader and its descendants.
+fn job_pids(snapshot: &Snapshot, job: &Job) -> Vec<u32> {
+    let mut pids = vec![job.pid];
+    pids.extend(snapshot.descendants(job.pid));
+    pids
+}
+
+/// The processes holding `port`, matched to the tracked jobs they belong
+/// to by ancestry or process group.
+fn which_port(store: &Store, port: u16) -> ServerResponse {
+    let sessions = cleanup_sessions(store);
+    let snapshot = Snapshot::take();
+    let tracked: Vec<(&Session, &Job, Vec<u32>)> = sessions
+        .iter()
+        .flat_map(|session| session.jobs.iter().map(move |job| (session, job)))
+        .map(|(session, job)| (session, job, job_pids(&snapshot, job)))
+        .collect();
+
+    let owners = SocketTable::read()
+        .owners(port)
+        .into_iter()
+        .map(|socket| {
+            let pgrp = proc_stat(socket.pid).map(|stat| stat.pgrp);
+            let owner = tracked
+                .iter()
+                .find(|(_, job, pids)| pids.contains(&socket.pid) || pgrp == Some(job.pid));
+            PortOwner {
+                command: snapshot
+                    .process(socket.pid)
+                    .map(command_line)
+                    .unwrap_or_default(),
+                directory: owner.map(|(session, ..)| session.directory.clone()),
+                job: owner.map(|(_, job, _)| (*job).clone()),
+                socket,
+            }
+        })
+        .collect();
+
+    ServerResponse::WhichPort { port, owners }
+}
+
+/// Page out the memory of the suspended job in `cwd` that `selector`
+/// refers to.
+fn freeze_tracked_job(store: &Store, cwd: &Path, selector: &str) -> ServerResponse {
+    // Paging out gigabytes takes a while, so the store is not held for it.
+    let job = match find_tracked_job(&mut lock(store), cwd, selector) {
+        Ok((job, _)) => job.clone(),
+        Err((code, message)) => return ServerResponse::error(code, message),
+    };
+    if !is_job_suspended(job.pid) {
+        return ServerResponse::error(
+            ErrorCode::InvalidRequest,
+            format!("Job %{} (PID {}) is not suspended", job.number, job.pid),
+        );
+    }
+
+    match freeze_job(store, cwd, job) {
+        Ok((job, reclaimed)) => ServerResponse::Freeze { job, reclaimed },
+        Err((pid, e)) => ServerResponse::error(ErrorCode::Internal, freeze_error(pid, &e)),
+    }
+}
+
+/// Page out every process in `job`'s process group and record how much was
+/// reclaimed, returning the updated job and the bytes reclaimed this time,
+/// or the process that could not be paged out and why.
+fn freeze_job(
+    store: &Store,
+    directory: &Path,
+    mut job: Job,
+) -> Result<(Job, u64), (u32, io::Error)> {
+    let mut pids = process_group(job.pid);
+    if pids.is_empty() {
+        pids.push(job.pid);
+    }
+
+    let mut reclaimed = 0;
+    for pid in pids {
+        match page_out(pid) {
+            Ok(bytes) => reclaimed += bytes,
+            // A child may have exited in the meantime.
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+            Err(e) => return Err((pid, e)),
+        }
+    }
+    info!(
+        "Paged out {} from job {} ({})",
+        format_bytes(reclaimed),
+        job.pid,
+        job.command
+    );
+
+    job.frozen = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    job.reclaimed += reclaimed;
+
+    let mut sessions = lock(store);
+    if let Some(tracked) = sessions
+        .iter_mut()
+        .filter(|s| s.directory == directory)
+        .flat_map(|s| s.jobs.iter_mut())
+        .find(|j| j.pid == job.pid)
+    {
+        tracked.frozen = job.frozen;
+        tracked.reclaimed = job.reclaimed;
+    }
+
+    Ok((job, reclaimed))
+}
+
+/// Describe why [`freeze_job`] failed to page out `pid`.
+fn freeze_error(pid: u32, error: &io::Error) -> String {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => format!(
+            "Not permitted to page out PID {}: job-server needs CAP_SYS_NICE to \
+             freeze jobs, e.g. `sudo setcap cap_sys_nice+ep $(command -v job-server)`",
+            pid
+        ),
+        _ => format!("Failed to page out PID {}: {}", pid, error),
+    }
+}
+
+/// How often [`kill_session`] checks whether jobs have exited.
+const KILL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long [`kill_session`] waits for jobs to die after SIGKILL.
+const KILL_WAIT: Duration = Duration::from_secs(1);
+
+/// Longest grace period [`kill_session`] allows, so the reply arrives well
+/// within the client's I/O timeout.
+pub const MAX_KILL_GRACE: Duration = Duration::from_secs(20);
+
+/// The grace period for a `kill_session` request, capped at
+/// [`MAX_KILL_GRACE`].
+fn kill_grace(grace_ms: u64) -> Duration {
+    Duration::from_millis(grace_ms).min(MAX_KILL_GRACE)
+}
+
+/// Remove the session for `dir` and terminate its jobs: SIGCONT and SIGTERM,
+/// then SIGKILL for any still alive after `grace`.
+fn kill_session(store: &Store, dir: &Path, grace: Duration) -> ServerResponse {
+    let session = {
+        let mut sessions = lock(store);
+        match sessions.iter().position(|s| s.directory == dir) {
+            Some(index) => sessions.remove(index),
+            None => {
+                return ServerResponse::error(
+                    ErrorCode::NotFound,
+                    format!("No jobs found for directory {}", dir.display()),
+                );
+            }
+        }
+    };
+    info!("Killing session {:?}", session);
+
+    let mut killed: Vec<KilledJob> = session
+        .jobs
+        .into_iter()
+        .map(|job| {
+            let (outcome, error) = match job.process() {
+                None => (KillOutcome::Gone, None),
+                Some(_) => match terminate_job(job.pid, JobsPolicy::Term) {
+                    Ok(()) => (KillOutcome::Terminated, None),
+                    Err(e) => (KillOutcome::Failed, Some(e.to_string())),
+                },
+            };
+            KilledJob {
+                job,
+                outcome,
+                error,
+            }
+        })
+        .collect();
+
+    let alive = |killed: &[KilledJob], outcome| {
+        killed
+            .iter()
+            .any(|k| k.outcome == outcome && k.job.process().is_some())
+    };
+
+    let started = Instant::now();
+    while started.elapsed() < grace && alive(&killed, KillOutcome::Terminated) {
+        thread::sleep(KILL_POLL_INTERVAL);
+    }
+
+    for k in &mut killed {
+        if k.outcome == KillOutcome::Terminated && k.job.process().is_some() {
+            info!("Job {} outlived SIGTERM, sending SIGKILL", k.job.pid);
+            k.outcome = match terminate_job(k.job.pid, JobsPolicy::Kill) {
+                Ok(()) => KillOutcome::Killed,
+                Err(e) => {
+                    k.error = Some(e.to_string());
+                    KillOutcome::Failed
+                }
+            };
+        }
+    }
+
+    let started = Instant::now();
+    while started.elapsed() < KILL_WAIT && alive(&killed, KillOutcome::Killed) {
+        thread::sleep(KILL_POLL_INTERVAL);
+    }
+
+    for k in &mut killed {
+        if k.outcome == KillOutcome::Killed && k.job.process().is_some() {
+            k.outcome = KillOutcome::Survived;
+        }
+    }
+
+    ServerResponse::KillSession {
+        directory: session.directory,
+        jobs: killed,
+    }
+}
+
+/// Decode one framed request, describing any framing or parse failure.
+fn decode_request(message: io::Result<Option<Vec<u8>>>) -> Result<ClientRequest, String> {
+    let message = match message {
+        Ok(Some(message)) => message,
+        Ok(None) => return Err("Connection closed before a request was sent".to_string()),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(e.to_string()),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            return Err(format!(
+                "Timed out after {:?} waiting for request",
+                SERVER_IO_TIMEOUT
+            ));
+        }
+        Err(e) => return Err(format!("Failed to read request: {}", e)),
+    };
+
+    let req = parse_request(&message)?;
+    info!("{}", serde_json::to_string_pretty(&req).unwrap());
+    Ok(req)
+}
+
+pub fn handle_client(mut stream: UnixStream, server: &Server) -> io::Result<()> {
+    let _connection = server.connection();
+
+    stream.set_read_timeout(Some(SERVER_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(SERVER_IO_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let message = read_message(&mut reader);
+
+    if let Ok(Some(first)) = &message
+        && jsonrpc::is_jsonrpc(first)
+    {
+        let first = first.clone();
+        return serve_jsonrpc(first, reader, stream, server);
+    }
+
+    let response = match decode_request(message) {
+        Ok(req) => dispatch(req, server),
+        Err(message) => ServerResponse::error(ErrorCode::InvalidRequest, message),
+    };
+    server.record_response(&response);
+
+    let payload = serde_json::to_vec(&response).unwrap();
+    write_message(&mut stream, &payload)
+}
+
+/// Keep answering JSON-RPC messages on a connection until the client hangs
+/// up, stays quiet for [`JSONRPC_IDLE_TIMEOUT`], or the server shuts down.
+fn serve_jsonrpc(
+    first: Vec<u8>,
+    mut reader: BufReader<UnixStream>,
+    mut stream: UnixStream,
+    server: &Server,
+) -> io::Result<()> {
+    let mut message = first;
+
+    loop {
+        if let Some(reply) = jsonrpc::handle_message(&message, server) {
+            write_message(&mut stream, &reply)?;
+        }
+
+        if !wait_for_message(&reader, server)? {
+            return Ok(());
+        }
+
+        message = match read_message(&mut reader) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                let reply = jsonrpc::handle_message(b"", server).unwrap_or_default();
+                return write_message(&mut stream, &reply);
+            }
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+/// Wait for the next message on a long-lived connection, giving up when the
+/// client is idle too long or the server is shutting down.
+fn wait_for_message(reader: &BufReader<UnixStream>, server: &Server) -> io::Result<bool> {
+    if !reader.buffer().is_empty() {
+        return Ok(true);
+    }
+
+    let started = Instant::now();
+    let mut fd = libc::pollfd {
+        fd: reader.get_ref().as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    while started.elapsed() < JSONRPC_IDLE_TIMEOUT && server.shutdown_reason().is_none() {
+        match unsafe { libc::poll(&mut fd, 1, 1000) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => return Ok(true),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Accept connections until shutdown is requested, handling each one on its
+/// own thread, then wait for in-flight requests to finish.
+pub fn serve(listener: UnixListener, server: Arc<Server>) -> io::Result<Shutdown> {
+    let mut handlers: Vec<JoinHandle<()>> = vec![];
+
+    while server.wait_for_connection(&listener)? {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                handlers.retain(|handle| !handle.is_finished());
+
+                let server = Arc::clone(&server);
+                handlers.push(thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &server) {
+                        error!("Client Error: {}", e);
+                        server.record_error(format!("Client Error: {}", e));
+                    }
+                }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                error!("Socket Listener Error: {}", e);
+                server.request_shutdown(Shutdown::ListenerFailed);
+            }
+        }
+    }
+
+    info!("Waiting for {} in-flight request(s)", handlers.len());
+    for handle in handlers {
+        let _ = handle.join();
+    }
+
+    Ok(server.shutdown_reason().unwrap_or(Shutdown::ListenerFailed))
+}
+
+#[cfg(feature = "async")]
+pub async fn handle_client_async(
+    stream: tokio::net::UnixStream,
+    server: Arc<Server>,
+) -> io::Result<()> {
+    use tokio::io::BufReader;
+    use tokio::time::timeout;
+
+    let _connection = server.connection();
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    let message = timeout(SERVER_IO_TIMEOUT, read_message_async(&mut reader))
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+
+    if let Ok(Some(first)) = &message
+        && jsonrpc::is_jsonrpc(first)
+    {
+        let first = first.clone();
+        return serve_jsonrpc_async(first, reader, write_half, Arc::clone(&server)).await;
+    }
+
+    let response = match decode_request(message) {
+        // Dispatch takes the store lock and walks the process table, so keep
+        // it off the async worker threads.
+        Ok(req) => {
+            let server = Arc::clone(&server);
+            tokio::task::spawn_blocking(move || dispatch(req, &server))
+                .await
+                .unwrap_or_else(|e| ServerResponse::error(ErrorCode::Internal, e.to_string()))
+        }
+        Err(message) => ServerResponse::error(ErrorCode::InvalidRequest, message),
+    };
+    server.record_response(&response);
+
+    let payload = serde_json::to_vec(&response).unwrap();
+    timeout(
+        SERVER_IO_TIMEOUT,
+        write_message_async(&mut write_half, &payload),
+    )
+    .await
+    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+/// Async counterpart of [`serve_jsonrpc`].
+#[cfg(feature = "async")]
+async fn serve_jsonrpc_async(
+    first: Vec<u8>,
+    mut reader: tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>,
+    mut writer: tokio::net::unix::OwnedWriteHalf,
+    server: Arc<Server>,
+) -> io::Result<()> {
+    use tokio::io::AsyncBufReadExt;
+    use tokio::time::timeout;
+
+    let mut message = first;
+
+    loop {
+        let handler = Arc::clone(&server);
+        let reply =
+            tokio::task::spawn_blocking(move || jsonrpc::handle_message(&message, &handler))
+                .await
+                .map_err(io::Error::other)?;
+        if let Some(reply) = reply {
+            timeout(SERVER_IO_TIMEOUT, write_message_async(&mut writer, &reply))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))?;
+        }
+
+        // Wake up every second to notice shutdown; fill_buf is cancel safe.
+        let started = Instant::now();
+        loop {
+            if started.elapsed() >= JSONRPC_IDLE_TIMEOUT || server.shutdown_reason().is_some() {
+                return Ok(());
+            }
+            match timeout(Duration::from_secs(1), reader.fill_buf()).await {
+                Ok(Ok([])) => return Ok(()),
+                Ok(Ok(_)) => break,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {}
+            }
+        }
+
+        message = match timeout(SERVER_IO_TIMEOUT, read_message_async(&mut reader)).await {
+            Ok(Ok(Some(message))) => message,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                let reply = jsonrpc::handle_message(b"", &server).unwrap_or_default();
+                return write_message_async(&mut writer, &reply).await;
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+    }
+}
+
+/// Accept connections until shutdown is requested, handling each one as a
+/// tokio task, then wait for in-flight requests to finish.
+#[cfg(feature = "async")]
+pub async fn serve_async(
+    listener: tokio::net::UnixListener,
+    server: Arc<Server>,
+) -> io::Result<Shutdown> {
+    use tokio::io::unix::AsyncFd;
+    use tokio::task::JoinSet;
+
+    let wake = AsyncFd::new(server.wake_read.try_clone()?)?;
+    let mut handlers = JoinSet::new();
+
+    while server.shutdown_reason().is_none() {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let server = Arc::clone(&server);
+                    handlers.spawn(async move {
+                        if let Err(e) = handle_client_async(stream, Arc::clone(&server)).await {
+                            error!("Client Error: {}", e);
+                            server.record_error(format!("Client Error: {}", e));
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Socket Listener Error: {}", e);
+                    server.request_shutdown(Shutdown::ListenerFailed);
+                }
+            },
+            guard = wake.readable() => {
+                guard?.retain_ready();
+            }
+            Some(_) = handlers.join_next(), if !handlers.is_empty() => {}
+        }
+    }
+
+    info!("Waiting for {} in-flight request(s)", handlers.len());
+    while handlers.join_next().await.is_some() {}
+
+    Ok(server.shutdown_reason().unwrap_or(Shutdown::ListenerFailed))
+}
+
+/// Why the server stopped accepting connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shutdown {
+    /// A client sent `kill`.
+    Kill(JobsPolicy),
+    /// A client asked for the server to be replaced by a new binary.
+    Upgrade(PathBuf),
+    /// No jobs and no clients for the configured idle timeout.
+    Idle,
+    /// The process received SIGTERM, SIGINT or SIGHUP.
+    Signal(i32),
+    /// Accepting on the listening socket failed.
+    ListenerFailed,
+}
+
+/// State shared by every connection handler.
+pub struct Server {
+    pub store: Store,
+    pub metrics: Arc<Metrics>,
+    socket_path: PathBuf,
+    started: SystemTime,
+    connections: AtomicUsize,
+    subscribers: AtomicUsize,
+    last_activity: Mutex<Instant>,
+    last_error: Mutex<Option<String>>,
+    /// Jobs already warned about by a stale job policy, by PID and start time.
+    /// Pruned by [`Server::collect_garbage`] once a job is no longer tracked.
+    warned: Mutex<HashSet<(u32, u64)>>,
+    /// Set once a freeze policy is refused for lack of CAP_SYS_NICE, which
+    /// would fail the same way for every job.
+    freeze_denied: AtomicBool,
+    shutdown: Mutex<Option<Shutdown>>,
+    wake_read: OwnedFd,
+    wake_write: OwnedFd,
+}
+
+impl Server {
+    pub fn new(sessions: Vec<Session>, socket_path: PathBuf) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            store: Arc::new(Mutex::new(sessions)),
+            metrics: Arc::new(Metrics::default()),
+            socket_path,
+            started: SystemTime::now(),
+            connections: AtomicUsize::new(0),
+            subscribers: AtomicUsize::new(0),
+            last_activity: Mutex::new(Instant::now()),
+            last_error: Mutex::new(None),
+            warned: Mutex::new(HashSet::new()),
+            freeze_denied: AtomicBool::new(false),
+            shutdown: Mutex::new(None),
+            wake_read: unsafe { OwnedFd::from_raw_fd(fds[0]) },
+            wake_write: unsafe { OwnedFd::from_raw_fd(fds[1]) },
+        })
+    }
+
+    /// Snapshot of the server's own health for the `status` action.
+    pub fn status(&self) -> ServerStatus {
+        let sessions = lock(&self.store);
+        let started = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::new(0, 0))
+            .as_secs();
+
+        ServerStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pid: std::process::id(),
+            socket_path: self.socket_path.clone(),
+            started,
+            uptime: self.started.elapsed().unwrap_or_default().as_secs(),
+            sessions: sessions.len(),
+            jobs: sessions.iter().map(|s| s.jobs.len()).sum(),
+            connections: self.connections.load(Ordering::Relaxed),
+            subscribers: self.subscribers.load(Ordering::Relaxed),
+            last_error: lock(&self.last_error).clone(),
+        }
+    }
+
+    /// Prometheus text for the `metrics` action and the HTTP endpoint.
+    pub fn metrics_text(&self) -> String {
+        let status = self.status();
+        let sessions = lock(&self.store).clone();
+        self.metrics.render(&sessions, &status)
+    }
+
+    /// Count a client connection for as long as the returned guard lives.
+    pub fn connection(&self) -> ConnectionGuard<'_> {
+        ConnectionGuard::new(&self.connections, &self.last_activity)
+    }
+
+    /// Count a long-lived event subscriber for as long as the guard lives.
+    pub fn subscriber(&self) -> ConnectionGuard<'_> {
+        ConnectionGuard::new(&self.subscribers, &self.last_activity)
+    }
+
+    /// Whether the server has had no jobs and no clients for `timeout`.
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        if self.connections.load(Ordering::Relaxed) > 0
+            || self.subscribers.load(Ordering::Relaxed) > 0
+            || lock(&self.last_activity).elapsed() < timeout
+        {
+            return false;
+        }
+
+        cleanup_sessions(&self.store).is_empty()
+    }
+
+    pub fn record_error(&self, message: String) {
+        *lock(&self.last_error) = Some(message);
+    }
+
+    /// Remember `response` as the last error if it reports a failure of the
+    /// server itself. Errors about the request, such as asking for a
+    /// directory with no jobs, are routine and left out.
+    pub(crate) fn record_response(&self, response: &ServerResponse) {
+        if let ServerResponse::Error {
+            code: code @ ErrorCode::Internal,
+            message,
+        } = response
+        {
+            self.record_error(format!("{:?}: {}", code, message));
+        }
+    }
+
+    /// Apply the stale job policies, or with `dry_run` only report which
+    /// jobs they apply to. Jobs already warned about are not reported again
+    /// unless it is a dry run.
+    pub fn collect_garbage(&self, dry_run: bool) -> Result<Vec<StaleJob>, String> {
+        let sessions = cleanup_sessions(&self.store);
+        self.forget_warnings(&sessions);
+
+        let policies = policies_file()
+            .map_err(|e| e.to_string())
+            .and_then(|path| load_policies(&path))?;
+        if policies.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let stale = stale_jobs(&policies, &sessions, now);
+        if dry_run {
+            return Ok(stale);
+        }
+
+        let mut applied = vec![];
+        for mut entry in stale {
+            let job = &entry.job;
+            let age = format_duration(entry.suspended_for);
+
+            match entry.action {
+                PolicyAction::Warn => {
+                    if !lock(&self.warned).insert((job.pid, job.start_time)) {
+                        continue;
+                    }
+                    warn!(
+                        "Job {} ({}) in {} has been suspended for {}",
+                        job.pid,
+                        job.command,
+                        entry.directory.display(),
+                        age
+                    );
+                    let message = format!(
+                        "job %{} ({}) has been suspended for {}",
+                        job.number, job.command, age
+                    );
+                    if let Err(e) = notify_terminal(job.pid, &message) {
+                        info!("Could not notify the terminal of job {}: {}", job.pid, e);
+                    }
+                }
+                PolicyAction::Freeze => {
+                    if job.frozen != 0 || self.freeze_denied.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    match freeze_job(&self.store, &entry.directory, job.clone()) {
+                        Ok((frozen, _)) => entry.job = frozen,
+                        Err((pid, e)) => {
+                            warn!("{}", freeze_error(pid, &e));
+                            if e.kind() == io::ErrorKind::PermissionDenied {
+                                warn!("Not applying freeze policies until job-server restarts");
+                                self.freeze_denied.store(true, Ordering::Relaxed);
+                            }
+                            continue;
+                        }
+                    }
+                }
+                PolicyAction::Term | PolicyAction::Kill => {
+                    let policy = match entry.action {
+                        PolicyAction::Kill => JobsPolicy::Kill,
+                        _ => JobsPolicy::Term,
+                    };
+                    warn!(
+                        "Sending {:?} to job {} ({}), suspended for {}",
+                        policy, job.pid, job.command, age
+                    );
+                    if let Err(e) = terminate_job(job.pid, policy) {
+                        warn!("Failed to signal job {}: {}", job.pid, e);
+                        continue;
+                    }
+
+                    let mut sessions = lock(&self.store);
+                    if let Some(session) =
+                        sessions.iter_mut().find(|s| s.directory == entry.directory)
+                    {
+                        session.jobs.retain(|j| j.pid != job.pid);
+                    }
+                    sessions.retain(|s| !s.jobs.is_empty());
+                }
+            }
+            applied.push(entry);
+        }
+
+        Ok(applied)
+    }
+
+    /// Ask the accept loop to stop. The first reason given wins.
+    pub fn request_shutdown(&self, reason: Shutdown) {
+        let mut shutdown = lock(&self.shutdown);
+        if shutdown.is_none() {
+            info!("Shutdown requested: {:?}", reason);
+            *shutdown = Some(reason);
+            unsafe { libc::write(self.wake_write.as_raw_fd(), [1u8].as_ptr().cast(), 1) };
+        }
+    }
+
+    pub fn shutdown_reason(&self) -> Option<Shutdown> {
+        lock(&self.shutdown).clone()
+    }
+
+    /// Block until `listener` has a pending connection (`true`) or shutdown
+    /// has been requested (`false`).
+    pub(crate) fn wait_for_connection(&self, listener: &impl AsRawFd) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.wake_read.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        loop {
+            if self.shutdown_reason().is_some() {
+                return Ok(false);
+            }
+
+            let rc = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if rc == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            if fds[1].revents != 0 {
+                continue;
+            }
+            if fds[0].revents != 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Drop the warnings about jobs no longer in `sessions`, so the set only
+    /// holds jobs that are still tracked.
+    fn forget_warnings(&self, sessions: &[Session]) {
+        let tracked: HashSet<(u32, u64)> = sessions
+            .iter()
+            .flat_map(|session| &session.jobs)
+            .map(|job| (job.pid, job.start_time))
+            .collect();
+        lock(&self.warned).retain(|key| tracked.contains(key));
+    }
+
+    /// Apply the requested jobs policy and persist whatever is left.
+    pub fn finish(&self, reason: &Shutdown, state_file: &Path) -> io::Result<()> {
+        let mut sessions = lock(&self.store);
+
+        if let Shutdown::Kill(policy) = *reason
+            && policy != JobsPolicy::Keep
+        {
+            // The store is only pruned lazily and may hold jobs restored
+            // from a previous run, so skip any whose PID now belongs to a
+            // different process.
+            for job in sessions.iter().flat_map(|s| s.jobs.iter()) {
+                if job.process().is_none() {
+                    info!("Job {} has already exited, not signalling it", job.pid);
+                    continue;
+                }
+                info!("Sending {:?} to job {:?}", policy, job);
+                if let Err(e) = terminate_job(job.pid, policy) {
+                    warn!("Failed to signal job {}: {}", job.pid, e);
+                }
+            }
+            sessions.clear();
+        }
+
+        info!(
+            "Saving {} session(s) to {}",
+            sessions.len(),
+            state_file.display()
+        );
+        save_sessions(state_file, &sessions)
+    }
+}
+
+/// Decrements a [`Server`] counter when dropped. Both ends count as
+/// activity for the idle timeout.
+pub struct ConnectionGuard<'a> {
+    counter: &'a AtomicUsize,
+    last_activity: &'a Mutex<Instant>,
+}
+
+impl<'a> ConnectionGuard<'a> {
+    fn new(counter: &'a AtomicUsize, last_activity: &'a Mutex<Instant>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        *lock(last_activity) = Instant::now();
+        Self {
+            counter,
+            last_activity,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        *lock(self.last_activity) = Instant::now();
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+fn terminate_job(pid: u32, policy: JobsPolicy) -> io::Result<()> {
+    match policy {
+        JobsPolicy::Keep => Ok(()),
+        JobsPolicy::Term => {
+            // Stopped processes only act on SIGTERM once continued.
+            signal_job(pid, libc::SIGCONT)?;
+            signal_job(pid, libc::SIGTERM)
+        }
+        JobsPolicy::Kill => signal_job(pid, libc::SIGKILL),
+    }
+}
+
+/// Block the shutdown signals in the calling thread and every thread it
+/// spawns afterwards. Call before starting any threads.
+pub fn block_shutdown_signals() -> io::Result<()> {
+    let set = shutdown_signals();
+    let rc = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    Ok(())
+}
+
+/// Turn SIGTERM, SIGINT and SIGHUP into a graceful shutdown request.
+///
+/// Relies on [`block_shutdown_signals`] having been called first so the
+/// signals queue up for `sigwait` instead of killing the process.
+pub fn spawn_signal_handler(server: Arc<Server>) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || {
+            let set = shutdown_signals();
+            let mut sig = 0;
+            loop {
+                if unsafe { libc::sigwait(&set, &mut sig) } == 0 {
+                    info!("Received signal {}", sig);
+                    server.request_shutdown(Shutdown::Signal(sig));
+                }
+            }
+        })
+}
+
+/// Apply the stale job policies every [`POLICY_INTERVAL`].
+pub fn spawn_policy_enforcer(server: Arc<Server>) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("policies".into())
+        .spawn(move || {
+            while server.shutdown_reason().is_none() {
+                thread::sleep(POLICY_INTERVAL);
+                if let Err(e) = server.collect_garbage(false) {
+                    error!("{}", e);
+                    server.record_error(e);
+                }
+            }
+        })
+}
+
+/// How often [`spawn_reaper`] checks whether adopted jobs have exited.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reap `pids`, the `run` jobs this process inherited from the server it
+/// replaced in an upgrade, and count how they exit. Their waiter threads did
+/// not survive the exec, so without this they would be left as zombies.
+pub fn spawn_reaper(server: Arc<Server>, mut pids: Vec<u32>) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new().name("reaper".into()).spawn(move || {
+        while !pids.is_empty() && server.shutdown_reason().is_none() {
+            pids.retain(|&pid| match try_reap(pid) {
+                Ok(Some(status)) => {
+                    info!("Job {} exited: {}", pid, status);
+                    server.metrics.record_exit(&status);
+                    false
+                }
+                Ok(None) => true,
+                Err(e) => {
+                    warn!("Failed to wait for job {}: {}", pid, e);
+                    false
+                }
+            });
+            thread::sleep(REAP_INTERVAL);
+        }
+    })
+}
+
+/// Shut the server down once it has been idle for `timeout`.
+pub fn spawn_idle_watchdog(server: Arc<Server>, timeout: Duration) -> io::Result<JoinHandle<()>> {
+    let interval = (timeout / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+
+    thread::Builder::new().name("idle".into()).spawn(move || {
+        while server.shutdown_reason().is_none() {
+            thread::sleep(interval);
+            if server.is_idle(timeout) {
+                info!("Idle for {:?}, exiting", timeout);
+                server.request_shutdown(Shutdown::Idle);
+            }
+        }
+    })
+}
+
+fn shutdown_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{IoClass, IoPriority};
+    use crate::sessions::load_sessions;
+    use std::fs;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Child;
+
+    fn server(sessions: Vec<Session>) -> Server {
+        Server::new(sessions, PathBuf::from("/nonexistent/jobctl.sock")).unwrap()
+    }
+
+    fn request(server: &Server, action: Commands, cwd: &str) -> ServerResponse {
+        dispatch(
+            ClientRequest {
+                action,
+                cwd: PathBuf::from(cwd),
+            },
+            server,
+        )
+    }
+
+    fn error_code(response: ServerResponse) -> ErrorCode {
+        match response {
+            ServerResponse::Error { code, .. } => code,
+            response => panic!("expected an error, got {:?}", response),
+        }
+    }
+
+    /// A session in `dir` tracking this test process, which is always alive.
+    fn session(dir: &str) -> Session {
+        Session {
+            jobs: vec![Job::new(
+                std::process::id(),
+                1,
+                "cargo test".to_string(),
+                JobState::Running,
+            )],
+            directory: PathBuf::from(dir),
+            usage: None,
+        }
+    }
+
+    /// Start `sleep` in its own process group, like a job started with `run`.
+    fn spawn_sleep() -> Child {
+        ProcessCommand::new("sleep")
+            .arg("30")
+            .process_group(0)
+            .spawn()
+            .unwrap()
+    }
+
+    fn job_for(child: &Child) -> Job {
+        Job::new(child.id(), 0, "sleep 30".to_string(), JobState::Running)
+    }
+
+    fn sessions_of(jobs: Vec<Job>) -> Vec<Session> {
+        vec![Session {
+            jobs,
+            directory: PathBuf::from("/project"),
+            usage: None,
+        }]
+    }
+
+    fn state_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("jobctl-{}-{}.json", name, std::process::id()))
+    }
+
+    fn run(options: RunOptions) -> Commands {
+        Commands::Run {
+            command: "true".to_string(),
+            options,
+        }
+    }
+
+    #[test]
+    fn reports_unknown_directories() {
+        let server = server(vec![session("/project")]);
+        let signal = Commands::Signal {
+            job: "%1".to_string(),
+            signal: 0,
+        };
+        assert_eq!(
+            error_code(request(&server, signal, "/elsewhere")),
+            ErrorCode::NotFound
+        );
+
+        let list = Commands::List {
+            fzf: false,
+            dir: Some("/elsewhere".to_string()),
+            sort: None,
+        };
+        assert_eq!(error_code(request(&server, list, "/")), ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn reports_unknown_jobs() {
+        let server = server(vec![session("/project")]);
+        for job in ["%2", "pid:1", "emacs"] {
+            let inspect = Commands::Inspect {
+                job: job.to_string(),
+                tree: false,
+                json: false,
+            };
+            assert_eq!(
+                error_code(request(&server, inspect, "/project")),
+                ErrorCode::NotFound,
+                "{}",
+                job
+            );
+        }
+
+        // The job itself is found.
+        let signal = Commands::Signal {
+            job: "%1".to_string(),
+            signal: 0,
+        };
+        assert!(matches!(
+            request(&server, signal, "/project"),
+            ServerResponse::Signal { .. }
+        ));
+    }
+
+    #[test]
+    fn reports_spawn_failures() {
+        let server = server(vec![]);
+        assert_eq!(
+            error_code(request(
+                &server,
+                run(RunOptions::default()),
+                "/nonexistent/dir"
+            )),
+            ErrorCode::SpawnFailed
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_priorities() {
+        let server = server(vec![session("/project")]);
+        let nice = RunOptions {
+            nice: Some(20),
+            ..RunOptions::default()
+        };
+        let ionice = RunOptions {
+            ionice: Some(IoPriority {
+                class: IoClass::BestEffort,
+                level: 8,
+            }),
+            ..RunOptions::default()
+        };
+        for options in [nice, ionice] {
+            assert_eq!(
+                error_code(request(&server, run(options), "/")),
+                ErrorCode::InvalidRequest,
+                "{:?}",
+                options
+            );
+        }
+
+        let renice = Commands::Renice {
+            job: "%1".to_string(),
+            nice: -21,
+        };
+        assert_eq!(
+            error_code(request(&server, renice, "/project")),
+            ErrorCode::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn rejects_client_side_actions() {
+        let server = server(vec![]);
+        let stop = Commands::Stop {
+            job: "%1".to_string(),
+        };
+        assert_eq!(
+            error_code(request(&server, stop, "/")),
+            ErrorCode::Unsupported
+        );
+    }
+
+    #[test]
+    fn reports_panics_as_internal_errors() {
+        let response = catch_panic(|| panic!("handler failed"));
+        assert!(matches!(
+            &response,
+            ServerResponse::Error { code: ErrorCode::Internal, message } if message == "handler failed"
+        ));
+
+        let response = catch_panic(|| panic!("{} failed", "formatted"));
+        assert!(matches!(
+            &response,
+            ServerResponse::Error { code: ErrorCode::Internal, message } if message == "formatted failed"
+        ));
+
+        assert!(matches!(
+            catch_panic(|| ServerResponse::Kill),
+            ServerResponse::Kill
+        ));
+    }
+
+    #[test]
+    fn shutdown_only_signals_live_tracked_jobs() {
+        let mut tracked = spawn_sleep();
+        let mut reused = spawn_sleep();
+        let mut exited = spawn_sleep();
+
+        // The PID now belongs to a different process than the one tracked.
+        let mut stale = job_for(&reused);
+        stale.start_time += 1;
+        let gone = job_for(&exited);
+        exited.kill().unwrap();
+        exited.wait().unwrap();
+
+        let server = server(sessions_of(vec![job_for(&tracked), stale, gone]));
+        let state_file = state_file("finish-kill");
+        server
+            .finish(&Shutdown::Kill(JobsPolicy::Kill), &state_file)
+            .unwrap();
+
+        assert_eq!(tracked.wait().unwrap().signal(), Some(libc::SIGKILL));
+        assert!(reused.try_wait().unwrap().is_none());
+        assert!(load_sessions(&state_file).is_empty());
+
+        reused.kill().unwrap();
+        reused.wait().unwrap();
+        fs::remove_file(state_file).unwrap();
+    }
+
+    #[test]
+    fn shutdown_continues_stopped_jobs_to_terminate_them() {
+        let mut child = spawn_sleep();
+        signal_job(child.id(), libc::SIGSTOP).unwrap();
+
+        let server = server(sessions_of(vec![job_for(&child)]));
+        let state_file = state_file("finish-term");
+        server
+            .finish(&Shutdown::Kill(JobsPolicy::Term), &state_file)
+            .unwrap();
+
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+        fs::remove_file(state_file).unwrap();
+    }
+
+    #[test]
+    fn shutdown_keeps_jobs_otherwise() {
+        let reasons = [
+            Shutdown::Kill(JobsPolicy::Keep),
+            Shutdown::Upgrade(PathBuf::from("/bin/true")),
+            Shutdown::Idle,
+            Shutdown::Signal(libc::SIGTERM),
+        ];
+        for reason in reasons {
+            let mut child = spawn_sleep();
+            let server = server(sessions_of(vec![job_for(&child)]));
+            let state_file = state_file("finish-keep");
+            server.finish(&reason, &state_file).unwrap();
+
+            assert!(child.try_wait().unwrap().is_none(), "{:?}", reason);
+            assert_eq!(load_sessions(&state_file)[0].jobs[0].pid, child.id());
+
+            child.kill().unwrap();
+            child.wait().unwrap();
+            fs::remove_file(state_file).unwrap();
+        }
+    }
+
+    #[test]
+    fn idles_without_jobs_or_clients() {
+        let server = server(vec![]);
+        assert!(server.is_idle(Duration::ZERO));
+        // Starting up counts as activity until the timeout passes.
+        assert!(!server.is_idle(Duration::from_secs(60)));
+
+        let connection = server.connection();
+        assert!(!server.is_idle(Duration::ZERO));
+        drop(connection);
+        assert!(server.is_idle(Duration::ZERO));
+
+        let subscriber = server.subscriber();
+        assert!(!server.is_idle(Duration::ZERO));
+        drop(subscriber);
+        assert!(server.is_idle(Duration::ZERO));
+    }
+
+    #[test]
+    fn live_jobs_keep_the_server_alive() {
+        let mut child = spawn_sleep();
+        let server = server(sessions_of(vec![job_for(&child)]));
+        assert!(!server.is_idle(Duration::ZERO));
+
+        // Once the job exits it is pruned and no longer counts.
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(server.is_idle(Duration::ZERO));
+        assert!(lock(&server.store).is_empty());
+    }
+
+    /// Start a job that ignores SIGTERM, once it is ready to.
+    fn spawn_stubborn() -> Child {
+        let child = ProcessCommand::new("sh")
+            .args(["-c", "trap '' TERM; exec sleep 30"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let comm = format!("/proc/{}/comm", child.id());
+        while fs::read_to_string(&comm).unwrap() != "sleep\n" {
+            thread::sleep(Duration::from_millis(10));
+        }
+        child
+    }
+
+    #[test]
+    fn kill_session_escalates_after_the_grace_period() {
+        let mut stubborn = spawn_stubborn();
+        let mut polite = spawn_sleep();
+        let mut exited = spawn_sleep();
+        let gone = job_for(&exited);
+        exited.kill().unwrap();
+        exited.wait().unwrap();
+
+        let jobs = vec![job_for(&stubborn), job_for(&polite), gone];
+        let server = server(sessions_of(jobs));
+        let grace = Duration::from_millis(300);
+        let started = Instant::now();
+        let response = kill_session(&server.store, Path::new("/project"), grace);
+        assert!(started.elapsed() >= grace);
+
+        let ServerResponse::KillSession { directory, jobs } = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(directory, Path::new("/project"));
+        let outcomes: Vec<KillOutcome> = jobs.iter().map(|k| k.outcome).collect();
+        assert_eq!(
+            outcomes,
+            [
+                KillOutcome::Killed,
+                KillOutcome::Terminated,
+                KillOutcome::Gone
+            ]
+        );
+        assert_eq!(stubborn.wait().unwrap().signal(), Some(libc::SIGKILL));
+        assert_eq!(polite.wait().unwrap().signal(), Some(libc::SIGTERM));
+        assert!(lock(&server.store).is_empty());
+    }
+
+    #[test]
+    fn kill_session_does_not_wait_for_exited_jobs() {
+        let mut polite = spawn_sleep();
+        let server = server(sessions_of(vec![job_for(&polite)]));
+        let started = Instant::now();
+        let response = kill_session(&server.store, Path::new("/project"), MAX_KILL_GRACE);
+        assert!(started.elapsed() < MAX_KILL_GRACE / 2);
+        assert!(matches!(response, ServerResponse::KillSession { .. }));
+        polite.wait().unwrap();
+
+        let again = Commands::KillSession {
+            dir: PathBuf::from("/project"),
+            grace_ms: 0,
+        };
+        assert_eq!(
+            error_code(request(&server, again, "/")),
+            ErrorCode::NotFound
+        );
+    }
+
+    #[test]
+    fn caps_the_kill_grace_period() {
+        assert_eq!(kill_grace(0), Duration::ZERO);
+        assert_eq!(kill_grace(1500), Duration::from_millis(1500));
+        assert_eq!(kill_grace(60_000), MAX_KILL_GRACE);
+        assert_eq!(kill_grace(u64::MAX), MAX_KILL_GRACE);
+    }
+
+    #[test]
+    fn forgets_warnings_about_untracked_jobs() {
+        let sessions = sessions_of(vec![Job::new(
+            std::process::id(),
+            1,
+            "cargo test".to_string(),
+            JobState::Running,
+        )]);
+        let tracked = &sessions[0].jobs[0];
+        let tracked = (tracked.pid, tracked.start_time);
+        let server = server(sessions.clone());
+        lock(&server.warned).extend([tracked, (tracked.0, tracked.1 + 1), (1, 0)]);
+
+        server.forget_warnings(&sessions);
+        assert_eq!(*lock(&server.warned), HashSet::from([tracked]));
+
+        server.forget_warnings(&[]);
+        assert!(lock(&server.warned).is_empty());
+    }
+}
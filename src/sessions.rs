@@ -1,46 +1,173 @@
 use base64::Engine;
 use base64::engine::general_purpose;
+use schemars::{JsonSchema, Schema, schema_for};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::io::{BufRead, BufReader, Write};
-use std::os::unix::net::UnixStream;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::{env, thread};
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::ClientError;
 use crate::cli::Commands;
-use crate::utils::is_job_suspended;
+use crate::policy::PolicyAction;
+use crate::utils::{ProcStat, lock, proc_stat, state_dir, unix_time};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct Job {
     pub pid: u32,
     pub command: String,
     pub number: u8,
+    /// When the job was last stopped, or when it was started if it has not
+    /// been stopped since, in seconds since the epoch.
     pub suspended: u64,
+    #[serde(default)]
+    pub state: JobState,
+    /// Start time of the process from `/proc/<pid>/stat`, used to tell
+    /// whether `pid` still refers to this job. 0 if unknown.
+    #[serde(default)]
+    pub start_time: u64,
+    /// When `freeze` last paged out the job's memory, 0 if it has not since
+    /// the job was last stopped.
+    #[serde(default)]
+    pub frozen: u64,
+    /// Bytes `freeze` has paged out of the job's processes so far.
+    #[serde(default)]
+    pub reclaimed: u64,
+    /// The most recent state changes, oldest first.
+    #[serde(default)]
+    pub history: Vec<StateChange>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// How many state changes a job remembers.
+const MAX_HISTORY: usize = 16;
+
+/// A job entering `state` at `at`, in seconds since the epoch.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct StateChange {
+    pub state: JobState,
+    pub at: u64,
+}
+
+impl Job {
+    /// A job for `pid` that starts out in `state` now.
+    pub fn new(pid: u32, number: u8, command: String, state: JobState) -> Self {
+        let now = unix_time();
+        Job {
+            pid,
+            command,
+            number,
+            suspended: now,
+            state,
+            start_time: proc_stat(pid).map_or(0, |stat| stat.start_time),
+            frozen: 0,
+            reclaimed: 0,
+            history: vec![StateChange { state, at: now }],
+        }
+    }
+
+    /// Move the job to `state`, remembering the change if it is one. A job
+    /// that stops counts as suspended from now.
+    pub fn set_state(&mut self, state: JobState) {
+        if self.state == state {
+            return;
+        }
+        let now = unix_time();
+        self.state = state;
+        if state == JobState::Stopped {
+            self.suspended = now;
+        }
+        self.history.push(StateChange { state, at: now });
+        if self.history.len() > MAX_HISTORY {
+            self.history.drain(..self.history.len() - MAX_HISTORY);
+        }
+    }
+
+    /// The job's process, if it is still alive and still the same process
+    /// that was recorded.
+    pub fn process(&self) -> Option<ProcStat> {
+        proc_stat(self.pid).filter(|stat| {
+            !stat.is_exited() && (self.start_time == 0 || stat.start_time == self.start_time)
+        })
+    }
+}
+
+/// Whether a tracked job is suspended or was continued in the background.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    #[default]
+    Stopped,
+    Running,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct JobOutput {
     pub pid: u32,
     pub command: String,
     pub number: u8,
     pub suspended: String,
+    pub state: JobState,
+    #[serde(default)]
+    pub usage: ResourceUsage,
+    /// TCP ports the job's processes are listening on.
+    #[serde(default)]
+    pub ports: Vec<u16>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// What a job's process and all its descendants are using.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct ResourceUsage {
+    /// Resident memory in bytes.
+    pub rss: u64,
+    /// Virtual memory in bytes.
+    pub virtual_memory: u64,
+    /// CPU time used so far, in milliseconds.
+    pub cpu_time_ms: u64,
+    pub threads: u64,
+    /// Number of descendant processes.
+    pub children: u64,
+}
+
+impl std::ops::AddAssign for ResourceUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.rss += other.rss;
+        self.virtual_memory += other.virtual_memory;
+        self.cpu_time_ms += other.cpu_time_ms;
+        self.threads += other.threads;
+        self.children += other.children;
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct Session {
     pub jobs: Vec<Job>,
     pub directory: PathBuf,
+    /// Totals over the session's jobs, only filled in by `list`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResourceUsage>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ServerStatus {
+    pub version: String,
+    pub pid: u32,
+    pub socket_path: PathBuf,
+    pub started: u64,
+    pub uptime: u64,
+    pub sessions: usize,
+    pub jobs: usize,
+    pub connections: usize,
+    pub subscribers: usize,
+    pub last_error: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct ClientRequest {
     #[serde(flatten)]
@@ -48,72 +175,307 @@ pub struct ClientRequest {
     pub cwd: PathBuf,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(tag = "action", rename_all = "snake_case")]
 pub enum ServerResponse {
-    ListJobs { jobs: Vec<JobOutput> },
-    ListSessions { sessions: Vec<Session> },
-    Register { job: Job },
+    ListJobs {
+        jobs: Vec<JobOutput>,
+    },
+    ListSessions {
+        sessions: Vec<Session>,
+    },
+    Register {
+        job: Job,
+    },
     Kill,
-    Error { message: String },
+    Upgrade {
+        pid: u32,
+    },
+    Status {
+        status: ServerStatus,
+    },
+    Signal {
+        job: Job,
+        signal: i32,
+    },
+    Freeze {
+        job: Job,
+        /// Bytes paged out by this request.
+        reclaimed: u64,
+    },
+    Inspect {
+        inspection: Box<Inspection>,
+    },
+    Renice {
+        job: Job,
+        nice: i32,
+        /// Number of threads whose niceness was changed.
+        threads: u32,
+    },
+    WhichPort {
+        port: u16,
+        owners: Vec<PortOwner>,
+    },
+    KillSession {
+        directory: PathBuf,
+        jobs: Vec<KilledJob>,
+    },
+    Gc {
+        jobs: Vec<StaleJob>,
+    },
+    Metrics {
+        text: String,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
 }
 
-pub fn encode_path(path: &Path) -> String {
-    general_purpose::URL_SAFE_NO_PAD.encode(path.to_string_lossy().as_bytes())
+impl ServerResponse {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerResponse::Error {
+            code,
+            message: message.into(),
+        }
+    }
 }
 
-pub fn start_server() {
-    let exe = env::current_exe().expect("Failed to get executable path");
-    let server_path = exe.with_file_name("job-server");
-
-    Command::new(server_path)
-        .spawn()
-        .expect("Failed to start server.");
-
-    thread::sleep(Duration::from_millis(500));
-}
-
-pub fn send_request(
-    request: ClientRequest,
-    should_start: Option<bool>,
-) -> Result<Value, ClientError> {
-    let should_start = should_start.unwrap_or(false);
-    let uid = unsafe { libc::getuid() };
-    let socket_path = format!("/tmp/jobctl-{}.sock", uid);
-    let mut resp_line = String::new();
-    let mut stream = match UnixStream::connect(&socket_path) {
-        Ok(s) => s,
-        Err(_) => {
-            if should_start {
-                start_server();
-                UnixStream::connect(&socket_path)?
-            } else {
-                return Err(ClientError::ServerNotRunning);
-            }
-        }
-    };
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let json = serde_json::to_string(&request)?;
+/// What happened to one job when its session was killed.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct KilledJob {
+    pub job: Job,
+    pub outcome: KillOutcome,
+    /// Why signalling the job failed, for [`KillOutcome::Failed`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KillOutcome {
+    /// The job had already exited.
+    Gone,
+    /// The job exited after SIGTERM.
+    Terminated,
+    /// The job ignored SIGTERM for the grace period and was killed.
+    Killed,
+    /// The job was still alive after SIGKILL.
+    Survived,
+    /// The job could not be signalled.
+    Failed,
+}
+
+/// What `inspect` reports about a tracked job.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Inspection {
+    pub directory: PathBuf,
+    pub job: Job,
+    /// Process group and session of the job leader.
+    pub pgid: u32,
+    pub sid: u32,
+    /// The job's parent: the shell that started it, or job-server for jobs
+    /// started with `run`.
+    pub shell_pid: u32,
+    pub shell: Option<String>,
+    /// Terminal the job writes to, if any.
+    pub tty: Option<PathBuf>,
+    /// Full command line of the job leader.
+    pub cmdline: Option<String>,
+    pub exe: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    /// When the job leader started, in seconds since the epoch.
+    pub started: Option<u64>,
+    pub usage: ResourceUsage,
+    /// File descriptors open in the job leader and its descendants, if they
+    /// can be listed.
+    pub open_files: Option<u64>,
+    /// Network and named Unix sockets held by the job's processes.
+    pub sockets: Vec<Socket>,
+    /// The job's processes, only filled in when asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree: Option<Vec<ProcessNode>>,
+}
+
+/// A socket held by a process.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Socket {
+    /// `tcp`, `tcp6`, `udp`, `udp6` or `unix`.
+    pub protocol: String,
+    /// Local address and port, or the path of a Unix socket.
+    pub address: String,
+    pub port: Option<u16>,
+    /// `listen` or a TCP state such as `established`; `bound` or
+    /// `connected` for UDP, `listen` or `connected` for Unix sockets.
+    pub state: String,
+    pub pid: u32,
+}
+
+/// A process holding the port `which_port` asked about.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PortOwner {
+    pub socket: Socket,
+    pub command: String,
+    /// The tracked job the process belongs to, and its session, if any.
+    pub directory: Option<PathBuf>,
+    pub job: Option<Job>,
+}
 
-    writeln!(stream, "{}", json)?;
+/// One process in a job's process tree.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ProcessNode {
+    pub pid: u32,
+    pub ppid: u32,
+    /// How far below the job leader, or below a reparented member of its
+    /// process group, the process is.
+    pub depth: u32,
+    pub state: String,
+    pub command: String,
+    /// Not stopped even though the job leader is, so it keeps running while
+    /// the job looks suspended.
+    pub still_running: bool,
+}
+
+/// A job a stale job policy applies to.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct StaleJob {
+    pub directory: PathBuf,
+    pub job: Job,
+    pub action: PolicyAction,
+    /// Seconds since the job was suspended.
+    pub suspended_for: u64,
+}
+
+/// Machine-readable reason attached to `ServerResponse::Error`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The directory, session or job asked about is not tracked.
+    NotFound,
+    /// The server could not start the requested process.
+    SpawnFailed,
+    /// The request could not be parsed or its arguments are invalid.
+    InvalidRequest,
+    /// The action exists but is not handled by the server.
+    Unsupported,
+    /// Something went wrong inside the server.
+    Internal,
+}
+
+/// Protocol types `jobctl schema` can describe.
+pub const SCHEMA_TYPES: &[&str] = &[
+    "ClientRequest",
+    "ServerResponse",
+    "Job",
+    "JobOutput",
+    "Session",
+    "Inspection",
+];
+
+/// JSON Schema for one of [`SCHEMA_TYPES`], generated from the Rust type.
+pub fn schema(name: &str) -> Option<Schema> {
+    match name {
+        "ClientRequest" => Some(schema_for!(ClientRequest)),
+        "ServerResponse" => Some(schema_for!(ServerResponse)),
+        "Job" => Some(schema_for!(Job)),
+        "JobOutput" => Some(schema_for!(JobOutput)),
+        "Session" => Some(schema_for!(Session)),
+        "Inspection" => Some(schema_for!(Inspection)),
+        _ => None,
+    }
+}
 
-    reader.read_line(&mut resp_line)?;
+/// Find the job in `jobs` that `selector` refers to: `%N` for a job number,
+/// `pid:N` for a PID, a bare number for a job number or else a PID, or
+/// otherwise a substring of the command. Job numbers are not unique within a directory, since
+/// several shells can share one and jobs started with `run` are all 0, so a
+/// selector that matches more than one job is rejected.
+pub fn select_job(jobs: &[Job], selector: &str) -> Result<usize, (ErrorCode, String)> {
+    let matching = |matches: fn(&Job, u32) -> bool, value: u32| -> Vec<usize> {
+        (0..jobs.len())
+            .filter(|&i| matches(&jobs[i], value))
+            .collect()
+    };
 
-    if resp_line.trim().is_empty() {
-        return Err(ClientError::EmptyResponse);
+    let matches = if let Some(number) = selector.strip_prefix('%') {
+        let number: u8 = number.parse().map_err(|_| {
+            (
+                ErrorCode::InvalidRequest,
+                format!("Invalid job number `{}`", selector),
+            )
+        })?;
+        matching(|job, n| u32::from(job.number) == n, number.into())
+    } else if let Some(pid) = selector.strip_prefix("pid:") {
+        let pid: u32 = pid.parse().map_err(|_| {
+            (
+                ErrorCode::InvalidRequest,
+                format!("Invalid PID `{}`", selector),
+            )
+        })?;
+        matching(|job, pid| job.pid == pid, pid)
+    } else if let Ok(number) = selector.parse::<u32>() {
+        let by_number = matching(|job, n| u32::from(job.number) == n, number);
+        match by_number.is_empty() {
+            true => matching(|job, pid| job.pid == pid, number),
+            false => by_number,
+        }
+    } else {
+        (0..jobs.len())
+            .filter(|&i| jobs[i].command.contains(selector))
+            .collect()
+    };
+
+    match matches.as_slice() {
+        [] => Err((
+            ErrorCode::NotFound,
+            format!("No job matching `{}` in this directory", selector),
+        )),
+        [index] => Ok(*index),
+        _ => Err((
+            ErrorCode::InvalidRequest,
+            format!(
+                "`{}` matches several jobs: {}",
+                selector,
+                matches
+                    .iter()
+                    .map(|&i| format!(
+                        "%{} ({}, PID {})",
+                        jobs[i].number, jobs[i].command, jobs[i].pid
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )),
     }
+}
 
-    let response: serde_json::Value = serde_json::from_str(&resp_line)?;
-    Ok(response)
+pub fn encode_path(path: &Path) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(path.to_string_lossy().as_bytes())
 }
 
 pub fn cleanup_sessions(store: &Arc<Mutex<Vec<Session>>>) -> Vec<Session> {
-    let mut sessions = store.lock().unwrap();
+    let mut sessions = lock(store);
 
     info!("Pruning Sessions: {:?}", sessions);
 
+    // Stopped jobs stay until they exit. A job that is running again is only
+    // kept if it was continued through jobctl; otherwise the shell brought
+    // it to the foreground and it is no longer a background job.
     sessions.iter_mut().for_each(|session| {
-        session.jobs.retain(|job| is_job_suspended(job.pid));
+        session.jobs.retain_mut(|job| match job.process() {
+            Some(stat) if stat.is_stopped() => {
+                job.set_state(JobState::Stopped);
+                true
+            }
+            Some(_) => job.state == JobState::Running,
+            None => false,
+        });
     });
 
     sessions.retain(|session| !session.jobs.is_empty());
@@ -122,3 +484,134 @@ pub fn cleanup_sessions(store: &Arc<Mutex<Vec<Session>>>) -> Vec<Session> {
 
     sessions.to_vec()
 }
+
+/// File the server persists its sessions to between runs.
+pub fn sessions_file() -> io::Result<PathBuf> {
+    Ok(state_dir()?.join("sessions.json"))
+}
+
+/// Load sessions saved by a previous server, or nothing if there are none.
+pub fn load_sessions(path: &Path) -> Vec<Session> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable state file {}: {}", path.display(), e);
+            vec![]
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => {
+            warn!("Failed to read state file {}: {}", path.display(), e);
+            vec![]
+        }
+    }
+}
+
+/// Atomically replace the state file with `sessions`.
+pub fn save_sessions(path: &Path, sessions: &[Session]) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(sessions)?)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(pid: u32, number: u8, command: &str) -> Job {
+        Job {
+            pid,
+            command: command.to_string(),
+            number,
+            suspended: 0,
+            state: JobState::Stopped,
+            start_time: 0,
+            frozen: 0,
+            reclaimed: 0,
+            history: vec![],
+        }
+    }
+
+    fn jobs() -> Vec<Job> {
+        vec![
+            job(100, 1, "vim notes.md"),
+            job(200, 2, "cargo build"),
+            job(300, 0, "cargo watch"),
+            job(400, 0, "python -m http.server"),
+        ]
+    }
+
+    #[test]
+    fn selects_by_job_number() {
+        assert_eq!(select_job(&jobs(), "%1"), Ok(0));
+        assert_eq!(select_job(&jobs(), "%2"), Ok(1));
+        assert_eq!(select_job(&jobs(), "2"), Ok(1));
+    }
+
+    #[test]
+    fn selects_by_pid_when_no_number_matches() {
+        assert_eq!(select_job(&jobs(), "300"), Ok(2));
+        assert_eq!(
+            select_job(&jobs(), "%300").unwrap_err().0,
+            ErrorCode::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn selects_by_explicit_pid() {
+        let mut jobs = jobs();
+        jobs.push(job(2, 5, "make"));
+        assert_eq!(select_job(&jobs, "2"), Ok(1));
+        assert_eq!(select_job(&jobs, "pid:2"), Ok(4));
+        assert_eq!(select_job(&jobs, "pid:300"), Ok(2));
+        assert_eq!(
+            select_job(&jobs, "pid:1").unwrap_err().0,
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            select_job(&jobs, "pid:x").unwrap_err().0,
+            ErrorCode::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn selects_by_command_substring() {
+        assert_eq!(select_job(&jobs(), "vim"), Ok(0));
+        assert_eq!(select_job(&jobs(), "http"), Ok(3));
+    }
+
+    #[test]
+    fn rejects_ambiguous_selectors() {
+        for selector in ["%0", "0", "cargo"] {
+            let (code, message) = select_job(&jobs(), selector).unwrap_err();
+            assert_eq!(code, ErrorCode::InvalidRequest, "{}", selector);
+            assert!(message.contains("matches several jobs"), "{}", message);
+        }
+
+        // Two shells in the same directory each have a %1.
+        let mut jobs = jobs();
+        jobs.push(job(500, 1, "less log.txt"));
+        let (code, message) = select_job(&jobs, "%1").unwrap_err();
+        assert_eq!(code, ErrorCode::InvalidRequest);
+        assert!(
+            message.contains("PID 100") && message.contains("PID 500"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn reports_missing_jobs() {
+        assert_eq!(
+            select_job(&jobs(), "%7").unwrap_err().0,
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            select_job(&jobs(), "12345").unwrap_err().0,
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            select_job(&jobs(), "emacs").unwrap_err().0,
+            ErrorCode::NotFound
+        );
+        assert_eq!(select_job(&[], "%1").unwrap_err().0, ErrorCode::NotFound);
+    }
+}
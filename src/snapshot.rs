@@ -0,0 +1,142 @@
+//! A point-in-time view of the system's processes, used to report what
+//! tracked jobs and everything they started are using.
+
+use std::collections::{HashMap, HashSet};
+
+use sysinfo::{Pid, Process, ProcessRefreshKind, ProcessStatus, RefreshKind, System};
+
+use crate::sessions::{ProcessNode, ResourceUsage};
+use crate::utils::process_group;
+
+pub struct Snapshot {
+    sys: System,
+    /// Child processes by parent, threads excluded.
+    children: HashMap<Pid, Vec<Pid>>,
+}
+
+impl Snapshot {
+    /// Read every process on the system, including its memory, CPU time and
+    /// threads.
+    pub fn take() -> Self {
+        let sys = System::new_with_specifics(
+            RefreshKind::nothing().with_processes(ProcessRefreshKind::everything()),
+        );
+
+        let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+        for (pid, process) in sys.processes() {
+            if process.thread_kind().is_some() {
+                continue;
+            }
+            if let Some(parent) = process.parent() {
+                children.entry(parent).or_default().push(*pid);
+            }
+        }
+        children.values_mut().for_each(|pids| pids.sort());
+
+        Self { sys, children }
+    }
+
+    pub fn process(&self, pid: u32) -> Option<&Process> {
+        self.sys.process(Pid::from(pid as usize))
+    }
+
+    /// Every descendant of `pid`, each one after its parent.
+    pub fn descendants(&self, pid: u32) -> Vec<u32> {
+        let mut found = vec![];
+        let mut pending = vec![Pid::from(pid as usize)];
+
+        while let Some(parent) = pending.pop() {
+            for child in self.children.get(&parent).into_iter().flatten().rev() {
+                found.push(child.as_u32());
+                pending.push(*child);
+            }
+        }
+
+        found
+    }
+
+    /// The process tree of the job led by `pid`: the leader and its
+    /// descendants, then any other members of its process group that have
+    /// been reparented elsewhere, with their own descendants. Each process
+    /// comes after its parent.
+    pub fn tree(&self, leader: u32) -> Vec<ProcessNode> {
+        let leader_stopped = self
+            .sys
+            .process(Pid::from(leader as usize))
+            .is_some_and(|p| p.status() == ProcessStatus::Stop);
+
+        let mut roots = vec![leader];
+        roots.extend(process_group(leader).into_iter().filter(|&p| p != leader));
+
+        let mut nodes = vec![];
+        let mut seen = HashSet::new();
+        for root in roots {
+            let mut pending = vec![(Pid::from(root as usize), 0)];
+            while let Some((pid, depth)) = pending.pop() {
+                if !seen.insert(pid) {
+                    continue;
+                }
+                let Some(process) = self.sys.process(pid) else {
+                    continue;
+                };
+
+                let state = process.status();
+                nodes.push(ProcessNode {
+                    pid: pid.as_u32(),
+                    ppid: process.parent().map_or(0, |p| p.as_u32()),
+                    depth,
+                    state: state.to_string().to_lowercase(),
+                    command: command_line(process),
+                    still_running: leader_stopped
+                        && pid.as_u32() != leader
+                        && !matches!(
+                            state,
+                            ProcessStatus::Stop | ProcessStatus::Zombie | ProcessStatus::Dead
+                        ),
+                });
+                for child in self.children.get(&pid).into_iter().flatten().rev() {
+                    pending.push((*child, depth + 1));
+                }
+            }
+        }
+
+        nodes
+    }
+
+    /// What `pid` and its descendants are using, or `None` if `pid` is not
+    /// running.
+    pub fn usage(&self, pid: u32) -> Option<ResourceUsage> {
+        self.sys.process(Pid::from(pid as usize))?;
+
+        let descendants = self.descendants(pid);
+        let mut usage = ResourceUsage {
+            children: descendants.len() as u64,
+            ..ResourceUsage::default()
+        };
+
+        for pid in std::iter::once(pid).chain(descendants) {
+            let Some(process) = self.sys.process(Pid::from(pid as usize)) else {
+                continue;
+            };
+            usage.rss += process.memory();
+            usage.virtual_memory += process.virtual_memory();
+            usage.cpu_time_ms += process.accumulated_cpu_time();
+            // The task list leaves out the main thread.
+            usage.threads += 1 + process.tasks().map_or(0, |tasks| tasks.len()) as u64;
+        }
+
+        Some(usage)
+    }
+}
+
+/// The full command line of `process`, or its name if that is unreadable.
+pub fn command_line(process: &Process) -> String {
+    match process.cmd() {
+        [] => process.name().to_string_lossy().into_owned(),
+        cmd => cmd
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
@@ -0,0 +1,453 @@
+//! Sockets held by processes, found by matching the socket inodes in
+//! `/proc/<pid>/fd` against the tables in `/proc/net`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::sessions::Socket;
+
+/// `/proc/net` tables to read, and whether their addresses are IPv6.
+const INET_TABLES: &[(&str, bool)] = &[
+    ("tcp", false),
+    ("tcp6", true),
+    ("udp", false),
+    ("udp6", true),
+];
+
+/// TCP state codes from `include/net/tcp_states.h`.
+const TCP_STATES: &[&str] = &[
+    "",
+    "established",
+    "syn_sent",
+    "syn_recv",
+    "fin_wait1",
+    "fin_wait2",
+    "time_wait",
+    "close",
+    "close_wait",
+    "last_ack",
+    "listen",
+    "closing",
+];
+
+/// The `__SO_ACCEPTCON` flag in `/proc/net/unix`, set on listening sockets.
+const UNIX_ACCEPTCON: u32 = 0x10000;
+
+/// Every socket on the system in this network namespace, by inode.
+pub struct SocketTable {
+    sockets: HashMap<u64, Socket>,
+}
+
+impl SocketTable {
+    pub fn read() -> Self {
+        let mut sockets = HashMap::new();
+
+        for (name, ipv6) in INET_TABLES {
+            let Ok(table) = fs::read_to_string(format!("/proc/net/{}", name)) else {
+                continue;
+            };
+            for line in table.lines().skip(1) {
+                if let Some((inode, socket)) = parse_inet(name, *ipv6, line) {
+                    sockets.insert(inode, socket);
+                }
+            }
+        }
+
+        if let Ok(table) = fs::read_to_string("/proc/net/unix") {
+            for line in table.lines().skip(1) {
+                if let Some((inode, socket)) = parse_unix(line) {
+                    sockets.insert(inode, socket);
+                }
+            }
+        }
+
+        Self { sockets }
+    }
+
+    /// The sockets `pids` have open. Unnamed Unix sockets, such as the ends
+    /// of socket pairs, are left out.
+    pub fn held_by(&self, pids: &[u32]) -> Vec<Socket> {
+        let mut held = vec![];
+        for &pid in pids {
+            for inode in socket_inodes(pid) {
+                let Some(socket) = self.sockets.get(&inode) else {
+                    continue;
+                };
+                if socket.protocol == "unix" && socket.address.is_empty() {
+                    continue;
+                }
+                held.push(Socket {
+                    pid,
+                    ..socket.clone()
+                });
+            }
+        }
+
+        // A listening socket and the connections accepted from it share a
+        // local address, so the state is part of what makes one distinct.
+        held.sort_by(|a, b| {
+            (a.port, &a.address, &a.protocol, &a.state).cmp(&(
+                b.port,
+                &b.address,
+                &b.protocol,
+                &b.state,
+            ))
+        });
+        held.dedup_by(|a, b| {
+            a.protocol == b.protocol && a.address == b.address && a.state == b.state
+        });
+        held
+    }
+
+    /// Processes listening on TCP `port` or bound to UDP `port`, with the
+    /// socket each one holds.
+    pub fn owners(&self, port: u16) -> Vec<Socket> {
+        let inodes: HashMap<u64, &Socket> = self
+            .sockets
+            .iter()
+            .filter(|(_, s)| s.port == Some(port) && matches!(s.state.as_str(), "listen" | "bound"))
+            .map(|(inode, s)| (*inode, s))
+            .collect();
+        if inodes.is_empty() {
+            return vec![];
+        }
+
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return vec![];
+        };
+        let mut owners = vec![];
+        for pid in entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        {
+            for inode in socket_inodes(pid) {
+                if let Some(socket) = inodes.get(&inode) {
+                    owners.push(Socket {
+                        pid,
+                        ..(*socket).clone()
+                    });
+                }
+            }
+        }
+
+        owners.sort_by_key(|s| s.pid);
+        owners
+    }
+}
+
+/// TCP ports among `sockets` that are listening, in order.
+pub fn listening_ports(sockets: &[Socket]) -> Vec<u16> {
+    let mut ports: Vec<u16> = sockets
+        .iter()
+        .filter(|s| s.state == "listen")
+        .filter_map(|s| s.port)
+        .collect();
+    ports.sort();
+    ports.dedup();
+    ports
+}
+
+/// Inodes of the sockets `pid` has open, from the `socket:[inode]` links in
+/// `/proc/<pid>/fd`. Empty if they cannot be listed.
+fn socket_inodes(pid: u32) -> Vec<u64> {
+    let Ok(entries) = fs::read_dir(format!("/proc/{}/fd", pid)) else {
+        return vec![];
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let target = fs::read_link(entry.path()).ok()?;
+            let target = target.to_str()?;
+            target
+                .strip_prefix("socket:[")?
+                .strip_suffix(']')?
+                .parse()
+                .ok()
+        })
+        .collect()
+}
+
+/// One line of `/proc/net/{tcp,udp}{,6}`.
+fn parse_inet(protocol: &str, ipv6: bool, line: &str) -> Option<(u64, Socket)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let local = parse_address(fields.get(1)?, ipv6)?;
+    let state = u8::from_str_radix(fields.get(3)?, 16).ok()?;
+    let inode: u64 = fields.get(9)?.parse().ok()?;
+    if inode == 0 {
+        return None;
+    }
+
+    let state = match protocol.starts_with("udp") {
+        // An unconnected UDP socket shows up as `close`.
+        true if state == 7 => "bound",
+        true => "connected",
+        false => TCP_STATES.get(state as usize).copied().unwrap_or("unknown"),
+    };
+
+    Some((
+        inode,
+        Socket {
+            protocol: protocol.to_string(),
+            address: local.to_string(),
+            port: Some(local.port()),
+            state: state.to_string(),
+            pid: 0,
+        },
+    ))
+}
+
+/// An address such as `0100007F:0BB8`, where the IP is the kernel's bytes
+/// printed as native-endian 32-bit words.
+fn parse_address(text: &str, ipv6: bool) -> Option<SocketAddr> {
+    let (ip, port) = text.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let mut bytes = vec![];
+    for i in (0..ip.len()).step_by(8) {
+        let word = u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+
+    let ip = match ipv6 {
+        true => Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).into(),
+        false => Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).into(),
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// One line of `/proc/net/unix`.
+fn parse_unix(line: &str) -> Option<(u64, Socket)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+    let inode: u64 = fields.get(6)?.parse().ok()?;
+    let path = fields.get(7..).unwrap_or_default().join(" ");
+
+    Some((
+        inode,
+        Socket {
+            protocol: "unix".to_string(),
+            address: path,
+            port: None,
+            state: match flags & UNIX_ACCEPTCON {
+                0 => "connected",
+                _ => "listen",
+            }
+            .to_string(),
+            pid: 0,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    fn socket(protocol: &str, address: &str, port: Option<u16>, state: &str) -> Socket {
+        Socket {
+            protocol: protocol.to_string(),
+            address: address.to_string(),
+            port,
+            state: state.to_string(),
+            pid: 0,
+        }
+    }
+
+    fn assert_socket(parsed: Option<(u64, Socket)>, inode: u64, expected: Socket) {
+        let (parsed_inode, parsed) = parsed.expect("line should parse");
+        assert_eq!(parsed_inode, inode);
+        assert_eq!(
+            (parsed.protocol, parsed.address, parsed.port, parsed.state),
+            (
+                expected.protocol,
+                expected.address,
+                expected.port,
+                expected.state
+            )
+        );
+    }
+
+    // The kernel prints addresses as native-endian words, and these lines
+    // come from an x86-64 machine.
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            parse_address("0100007F:1F90", false),
+            Some("127.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("00000000:0035", false),
+            Some("0.0.0.0:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("0101A8C0:C350", false),
+            Some("192.168.1.1:50000".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("00000000000000000000000001000000:1F90", true),
+            Some("[::1]:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("0000000000000000FFFF00000100007F:0050", true),
+            Some("[::ffff:127.0.0.1]:80".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("B80D0120000000000000000001000000:01BB", true),
+            Some("[2001:db8::1]:443".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        assert_eq!(parse_address("0100007F", false), None);
+        assert_eq!(parse_address("0100007F:XYZ", false), None);
+        assert_eq!(parse_address("0100007:1F90", false), None);
+        assert_eq!(parse_address("0100007F:1F90", true), None);
+        assert_eq!(
+            parse_address("00000000000000000000000001000000:1F90", false),
+            None
+        );
+    }
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn parses_tcp_lines() {
+        assert_socket(
+            parse_inet(
+                "tcp",
+                false,
+                "   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  \
+                 1000        0 41230 1 0000000000000000 100 0 0 10 0",
+            ),
+            41230,
+            socket("tcp", "127.0.0.1:8080", Some(8080), "listen"),
+        );
+        assert_socket(
+            parse_inet(
+                "tcp",
+                false,
+                "   3: 0100007F:1F90 0100007F:D2A4 01 00000000:00000000 00:00000000 00000000  \
+                 1000        0 41377 1 0000000000000000 20 4 30 10 -1",
+            ),
+            41377,
+            socket("tcp", "127.0.0.1:8080", Some(8080), "established"),
+        );
+        assert_socket(
+            parse_inet(
+                "tcp6",
+                true,
+                "   0: 00000000000000000000000000000000:1538 00000000000000000000000000000000:0000 \
+                 0A 00000000:00000000 00:00000000 00000000  1000        0 52011 1 \
+                 0000000000000000 100 0 0 10 0",
+            ),
+            52011,
+            socket("tcp6", "[::]:5432", Some(5432), "listen"),
+        );
+    }
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn parses_udp_lines() {
+        assert_socket(
+            parse_inet(
+                "udp",
+                false,
+                "  123: 3500007F:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000  \
+                 101        0 18810 2 0000000000000000 0",
+            ),
+            18810,
+            socket("udp", "127.0.0.53:53", Some(53), "bound"),
+        );
+        assert_socket(
+            parse_inet(
+                "udp",
+                false,
+                "  401: 0F02000A:9C40 08080808:0035 01 00000000:00000000 00:00000000 00000000  \
+                 1000        0 66001 2 0000000000000000 0",
+            ),
+            66001,
+            socket("udp", "10.0.2.15:40000", Some(40000), "connected"),
+        );
+    }
+
+    #[test]
+    fn skips_inet_lines_without_a_socket() {
+        // TIME_WAIT entries no longer belong to a process.
+        assert!(
+            parse_inet(
+                "tcp",
+                false,
+                "   7: 0100007F:1F90 0100007F:D2A6 06 00000000:00000000 03:00001234 00000000  \
+                 0        0 0 3 0000000000000000",
+            )
+            .is_none()
+        );
+        assert!(parse_inet("tcp", false, "  sl  local_address rem_address   st").is_none());
+        assert!(parse_inet("tcp", false, "").is_none());
+    }
+
+    #[test]
+    fn parses_unix_lines() {
+        assert_socket(
+            parse_unix(
+                "0000000000000000: 00000002 00000000 00010000 0001 01 23456 /run/user/1000/jobctl.sock",
+            ),
+            23456,
+            socket("unix", "/run/user/1000/jobctl.sock", None, "listen"),
+        );
+        assert_socket(
+            parse_unix(
+                "0000000000000000: 00000003 00000000 00000000 0001 03 30211 @/tmp/.X11-unix/X0",
+            ),
+            30211,
+            socket("unix", "@/tmp/.X11-unix/X0", None, "connected"),
+        );
+        assert_socket(
+            parse_unix("0000000000000000: 00000002 00000000 00010000 0001 01 23999 /tmp/my socket"),
+            23999,
+            socket("unix", "/tmp/my socket", None, "listen"),
+        );
+        // Unnamed, such as one end of a socket pair.
+        assert_socket(
+            parse_unix("00000000501e4aef: 00000003 00000000 00000000 0001 03   972"),
+            972,
+            socket("unix", "", None, "connected"),
+        );
+        assert!(parse_unix("Num       RefCount Protocol Flags    Type St Inode Path").is_none());
+    }
+
+    #[test]
+    fn keeps_listening_and_accepted_sockets_apart() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (_accepted, _) = listener.accept().unwrap();
+        client.write_all(b"hello").unwrap();
+
+        let held = SocketTable::read().held_by(&[std::process::id()]);
+        let on_port: Vec<&str> = held
+            .iter()
+            .filter(|s| s.port == Some(port))
+            .map(|s| s.state.as_str())
+            .collect();
+
+        assert_eq!(on_port, ["established", "listen"]);
+        assert!(listening_ports(&held).contains(&port));
+    }
+
+    #[test]
+    fn lists_listening_ports_once() {
+        let sockets = [
+            socket("tcp", "127.0.0.1:8080", Some(8080), "established"),
+            socket("tcp6", "[::]:3000", Some(3000), "listen"),
+            socket("tcp", "0.0.0.0:3000", Some(3000), "listen"),
+            socket("udp", "0.0.0.0:53", Some(53), "bound"),
+            socket("unix", "/tmp/a.sock", None, "listen"),
+        ];
+        assert_eq!(listening_ports(&sockets), [3000]);
+    }
+}
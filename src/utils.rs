@@ -1,12 +1,431 @@
 use std::collections::HashMap;
+use std::fs;
 use std::io::Write;
-use std::process::{Command, Stdio};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use directories::ProjectDirs;
 use sysinfo::{Pid, ProcessRefreshKind, ProcessStatus, RefreshKind, System};
 
+use crate::cli::{IoClass, IoPriority, RunOptions};
 use crate::sessions::{JobOutput, Session};
 
+pub fn socket_path() -> PathBuf {
+    let uid = unsafe { libc::getuid() };
+    PathBuf::from(format!("/tmp/jobctl-{}.sock", uid))
+}
+
+/// Per-user directory for the pidfile, logs and other server state.
+///
+/// Uses `$XDG_STATE_HOME/jobctl` where available and is created on demand.
+/// Only the user may enter it, since the logs and the HTTP token live there.
+pub fn state_dir() -> std::io::Result<PathBuf> {
+    let dir = match ProjectDirs::from("", "", "jobctl") {
+        Some(dirs) => dirs
+            .state_dir()
+            .unwrap_or_else(|| dirs.data_local_dir())
+            .to_path_buf(),
+        None => std::env::temp_dir().join(format!("jobctl-{}", unsafe { libc::getuid() })),
+    };
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+/// Lock `mutex`, recovering it if a request handler panicked while holding
+/// it. The server turns such panics into error responses and carries on, so
+/// a poisoned lock must not fail every later request.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Send `signal` to the process group led by `pid`, falling back to the
+/// process itself when it does not lead a group of its own.
+pub fn signal_job(pid: u32, signal: i32) -> std::io::Result<()> {
+    let pid = pid as libc::pid_t;
+
+    if unsafe { libc::kill(-pid, signal) } == 0 {
+        return Ok(());
+    }
+    if unsafe { libc::kill(pid, signal) } == 0 {
+        return Ok(());
+    }
+
+    Err(std::io::Error::last_os_error())
+}
+
+/// `IOPRIO_WHO_PROCESS` from `linux/ioprio.h`.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// Apply the priority and limits in `options` to the calling process. Only
+/// makes system calls, so it is safe to run between fork and exec.
+pub fn apply_run_options(options: &RunOptions) -> std::io::Result<()> {
+    let check = |rc: libc::c_long| match rc {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    };
+
+    if let Some(nice) = options.nice {
+        check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) }.into())?;
+    }
+    if let Some(ionice) = options.ionice {
+        check(unsafe {
+            libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ionice.value())
+        })?;
+    }
+
+    // The kernel only sends SIGXCPU at the soft CPU limit; at the hard
+    // limit it sends SIGKILL, so that one is left alone.
+    let limits = [
+        (libc::RLIMIT_AS, options.max_mem, true),
+        (libc::RLIMIT_CPU, options.max_cpu_seconds, false),
+        (libc::RLIMIT_NOFILE, options.max_files, true),
+    ];
+    for (resource, limit, hard) in limits {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let mut rlimit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        check(unsafe { libc::getrlimit(resource, &mut rlimit) }.into())?;
+        rlimit.rlim_cur = limit;
+        if hard {
+            rlimit.rlim_max = limit;
+        }
+        check(unsafe { libc::setrlimit(resource, &rlimit) }.into())?;
+    }
+
+    Ok(())
+}
+
+/// Whether `options` ask for a higher priority than the calling process
+/// has, which needs CAP_SYS_NICE: a lower niceness or realtime I/O.
+pub fn raises_priority(options: &RunOptions) -> bool {
+    let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+
+    options.nice.is_some_and(|nice| nice < current)
+        || options
+            .ionice
+            .is_some_and(|ionice| ionice.class == IoClass::Realtime)
+}
+
+/// Set the niceness of every thread of `pid`, returning how many were
+/// changed. Niceness is per thread on Linux.
+pub fn renice(pid: u32, nice: i32) -> std::io::Result<usize> {
+    let mut changed = 0;
+    for entry in fs::read_dir(format!("/proc/{}/task", pid))?.flatten() {
+        let Some(tid) = entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } == -1 {
+            let error = std::io::Error::last_os_error();
+            // The thread may have exited since the directory was read.
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                return Err(error);
+            }
+            continue;
+        }
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+/// Signals that can be given by name, without the `SIG` prefix.
+pub const SIGNALS: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("WINCH", libc::SIGWINCH),
+];
+
+/// Parse a signal given as a number or a name such as `TERM` or `SIGTERM`.
+pub fn parse_signal(input: &str) -> Result<i32, String> {
+    let input = input.trim();
+    if let Ok(signal) = input.parse::<i32>() {
+        return match signal {
+            0..=64 => Ok(signal),
+            _ => Err(format!("invalid signal number {}", signal)),
+        };
+    }
+
+    let upper = input.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, signal)| *signal)
+        .ok_or_else(|| format!("unknown signal `{}`", input))
+}
+
+/// Name of `signal` as accepted by [`parse_signal`], or its number.
+pub fn signal_name(signal: i32) -> String {
+    SIGNALS
+        .iter()
+        .find(|(_, known)| *known == signal)
+        .map(|(name, _)| format!("SIG{}", name))
+        .unwrap_or_else(|| signal.to_string())
+}
+
+/// The fields of `/proc/<pid>/stat` jobctl cares about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcStat {
+    /// Scheduler state letter, e.g. `R`, `S`, `T` or `Z`.
+    pub state: char,
+    pub ppid: u32,
+    pub pgrp: u32,
+    pub session: u32,
+    /// Clock ticks after boot at which the process started. Together with
+    /// the PID this identifies a process even after the PID is reused.
+    pub start_time: u64,
+}
+
+impl ProcStat {
+    pub fn is_stopped(&self) -> bool {
+        matches!(self.state, 'T' | 't')
+    }
+
+    pub fn is_exited(&self) -> bool {
+        matches!(self.state, 'Z' | 'X' | 'x')
+    }
+}
+
+/// Read `/proc/<pid>/stat`, or `None` if there is no such process.
+pub fn proc_stat(pid: u32) -> Option<ProcStat> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is in parentheses and may itself contain spaces or
+    // parentheses, so split after the last `)`.
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split(' ').collect();
+
+    Some(ProcStat {
+        state: fields.first()?.chars().next()?,
+        ppid: fields.get(1)?.parse().ok()?,
+        pgrp: fields.get(2)?.parse().ok()?,
+        session: fields.get(3)?.parse().ok()?,
+        start_time: fields.get(19)?.parse().ok()?,
+    })
+}
+
+/// PIDs of the live processes in process group `pgrp`.
+pub fn process_group(pgrp: u32) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return vec![];
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter(|&pid| proc_stat(pid).is_some_and(|s| s.pgrp == pgrp && !s.is_exited()))
+        .collect()
+}
+
+/// PIDs of the live child processes of this process.
+pub fn child_processes() -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return vec![];
+    };
+    let me = std::process::id();
+
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter(|&pid| proc_stat(pid).is_some_and(|s| s.ppid == me))
+        .collect()
+}
+
+/// Reap child `pid` if it has exited, without blocking. Returns `None` while
+/// it is still running.
+pub fn try_reap(pid: u32) -> std::io::Result<Option<ExitStatus>> {
+    let mut status = 0;
+    match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => Ok(None),
+        _ => Ok(Some(ExitStatus::from_raw(status))),
+    }
+}
+
+/// Number of file descriptors `pid` has open, or `None` if they cannot be
+/// listed.
+pub fn open_files(pid: u32) -> Option<u64> {
+    Some(fs::read_dir(format!("/proc/{}/fd", pid)).ok()?.count() as u64)
+}
+
+/// Resident set size of `pid` in bytes, from `/proc/<pid>/statm`.
+pub fn resident_bytes(pid: u32) -> Option<u64> {
+    let statm = fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: u64 = statm.split(' ').nth(1)?.parse().ok()?;
+    Some(pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64)
+}
+
+/// Ask the kernel to push the memory of `pid` out to swap with
+/// `process_madvise(MADV_PAGEOUT)`, returning how much its resident set
+/// shrank. Needs Linux 5.10 or later, and CAP_SYS_NICE even for processes
+/// of the same user; without it this fails with `PermissionDenied`.
+pub fn page_out(pid: u32) -> std::io::Result<u64> {
+    let before = resident_bytes(pid).unwrap_or(0);
+
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let pidfd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    // Special mappings such as [vvar] cannot be advised and would fail the
+    // whole call, so only the heap, the stack and real mappings are included.
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    let ranges: Vec<libc::iovec> = maps
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let Some(name) = fields.get(5)
+                && name.starts_with('[')
+                && !matches!(*name, "[heap]" | "[stack]")
+            {
+                return None;
+            }
+            let (start, end) = fields.first()?.split_once('-')?;
+            let start = usize::from_str_radix(start, 16).ok()?;
+            let end = usize::from_str_radix(end, 16).ok()?;
+            Some(libc::iovec {
+                iov_base: start as *mut libc::c_void,
+                iov_len: end - start,
+            })
+        })
+        .collect();
+
+    let advise = |ranges: &[libc::iovec]| {
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_process_madvise,
+                pidfd.as_raw_fd(),
+                ranges.as_ptr(),
+                ranges.len(),
+                libc::MADV_PAGEOUT,
+                0,
+            )
+        };
+        match rc {
+            0.. => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+
+    for chunk in ranges.chunks(libc::UIO_MAXIOV as usize) {
+        match advise(chunk) {
+            Ok(()) => {}
+            // A locked or device mapping rejects the advice; retry the
+            // ranges one at a time and skip the ones that refuse.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                for range in chunk {
+                    match advise(std::slice::from_ref(range)) {
+                        Err(e) if e.raw_os_error() != Some(libc::EINVAL) => return Err(e),
+                        _ => {}
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let after = resident_bytes(pid).unwrap_or(before);
+    Ok(before.saturating_sub(after))
+}
+
+/// Terminal devices open as `pid`'s stdin, stdout or stderr.
+pub fn terminal_fds(pid: u32) -> Vec<(i32, PathBuf)> {
+    (0..=2)
+        .filter_map(|fd| {
+            let target = fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).ok()?;
+            is_terminal_path(&target).then_some((fd, target))
+        })
+        .collect()
+}
+
+/// The terminal our own stdin is attached to, if any.
+pub fn own_terminal() -> Option<PathBuf> {
+    fs::read_link("/proc/self/fd/0")
+        .ok()
+        .filter(|target| is_terminal_path(target))
+}
+
+fn is_terminal_path(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    path.starts_with("/dev/pts/") || path.starts_with("/dev/tty")
+}
+
+/// Whether `stty tostop` is set on `tty`, so background writes stop with
+/// SIGTTOU.
+pub fn terminal_stops_output(tty: &Path) -> bool {
+    let Ok(file) = fs::File::options()
+        .read(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(tty)
+    else {
+        return false;
+    };
+
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    let rc = unsafe { libc::tcgetattr(file.as_raw_fd(), &mut termios) };
+    rc == 0 && termios.c_lflag & libc::TOSTOP != 0
+}
+
+/// Print `message` on the terminal `pid` writes to, if it has one.
+pub fn notify_terminal(pid: u32, message: &str) -> std::io::Result<bool> {
+    let Some((_, tty)) = terminal_fds(pid).into_iter().rev().find(|(fd, _)| *fd > 0) else {
+        return Ok(false);
+    };
+
+    fs::File::options()
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(tty)?
+        .write_all(format!("\r\njobctl: {}\r\n", message).as_bytes())?;
+    Ok(true)
+}
+
+/// Reasons a job resumed in the background may be stopped again by its
+/// original terminal.
+pub fn background_warnings(pid: u32) -> Vec<String> {
+    let mut warnings = vec![];
+
+    for (fd, tty) in terminal_fds(pid) {
+        match fd {
+            0 => warnings.push(format!(
+                "stdin is {}, the job will stop again with SIGTTIN if it reads from it",
+                tty.display()
+            )),
+            _ if terminal_stops_output(&tty) => warnings.push(format!(
+                "{} is {} with `stty tostop` set, the job will stop again with SIGTTOU when it writes",
+                if fd == 1 { "stdout" } else { "stderr" },
+                tty.display()
+            )),
+            _ => {}
+        }
+    }
+
+    warnings.dedup();
+    warnings
+}
+
 pub fn is_job_suspended(pid: u32) -> bool {
     let sys = System::new_with_specifics(
         RefreshKind::nothing().with_processes(ProcessRefreshKind::everything()),
@@ -22,27 +441,154 @@ pub fn is_job_suspended(pid: u32) -> bool {
     }
 }
 
+/// Seconds since the epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::new(0, 0))
+        .as_secs()
+}
+
+/// Render seconds since the epoch as local time, e.g. `2024-05-01 14:03:22`.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let time = timestamp as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return timestamp.to_string();
+    }
+
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
 pub fn time_ago(timestamp: u64) -> String {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or(Duration::new(0, 0))
         .as_secs();
 
-    let diff = now - timestamp;
+    format!("{} ago", format_duration(now.saturating_sub(timestamp)))
+}
 
-    if diff < 60 {
-        format!("{}s ago", diff)
-    } else if diff < 3600 {
-        format!("{}m ago", diff / 60)
-    } else if diff < 86400 {
-        format!("{}h ago", diff / 3600)
-    } else if diff < 604800 {
-        format!("{}d ago", diff / 86400)
+/// Render a number of seconds in the largest whole unit, e.g. `3h`.
+pub fn format_duration(secs: u64) -> String {
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m", secs / 60)
+    } else if secs < 86400 {
+        format!("{}h", secs / 3600)
+    } else if secs < 604800 {
+        format!("{}d", secs / 86400)
     } else {
-        format!("{}w ago", diff / 604800)
+        format!("{}w", secs / 604800)
     }
 }
 
+/// Render a number of bytes in binary units, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Parse an I/O priority such as `idle`, `best-effort` or `realtime:2`.
+/// `be` and `rt` are accepted too, and the level defaults to 4.
+pub fn parse_ionice(input: &str) -> Result<IoPriority, String> {
+    let (class, level) = match input.split_once(':') {
+        Some((class, level)) => (class, Some(level)),
+        None => (input, None),
+    };
+
+    let class = match class.to_ascii_lowercase().as_str() {
+        "idle" => IoClass::Idle,
+        "be" | "best-effort" => IoClass::BestEffort,
+        "rt" | "realtime" => IoClass::Realtime,
+        _ => return Err(format!("unknown I/O scheduling class `{}`", class)),
+    };
+    let level = match level {
+        Some(level) => level
+            .parse()
+            .ok()
+            .filter(|level| *level <= 7)
+            .ok_or_else(|| format!("invalid I/O priority level `{}`, expected 0 to 7", level))?,
+        None => 4,
+    };
+
+    Ok(IoPriority { class, level })
+}
+
+/// Parse a size in bytes such as `4096`, `512K`, `512M` or `2G`, in binary
+/// units. A trailing `B` or `iB` is allowed.
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid size `{}`", input))?;
+
+    let shift = match unit.trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        _ => return Err(format!("invalid size unit `{}` in `{}`", unit, input)),
+    };
+
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{}` is too large", input))
+}
+
+/// Parse a duration such as `500ms`, `30s`, `5m`, `2h`, `1d` or `1w`.
+///
+/// A bare number is taken as seconds. Used as a clap `value_parser`.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", input))?;
+
+    let multiplier: u64 = match unit {
+        "ms" => return Ok(Duration::from_millis(value)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 604800,
+        _ => return Err(format!("invalid duration unit `{}` in `{}`", unit, input)),
+    };
+
+    value
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration `{}` is too long", input))
+}
+
 pub fn build_fzf_sessions_input(sessions: Vec<Session>) -> String {
     let mut input = String::new();
 
@@ -56,27 +602,52 @@ pub fn build_fzf_sessions_input(sessions: Vec<Session>) -> String {
 pub fn build_fzf_jobs_input(jobs: Vec<JobOutput>) -> (HashMap<u8, String>, String) {
     let mut jobs_map = HashMap::new();
 
-    jobs.iter().for_each(|job| {
-        jobs_map.insert(
-            job.number,
+    let lines: Vec<String> = jobs
+        .iter()
+        .map(|job| {
+            let ports: String = job
+                .ports
+                .iter()
+                .map(|port| format!(", :{}", port))
+                .collect();
             format!(
-                "[{}:{}] - {}, {} \n",
-                job.number, job.pid, job.command, job.suspended
-            ),
-        );
-    });
+                "[{}:{}] - {}, {}, {}{} \n",
+                job.number,
+                job.pid,
+                job.command,
+                job.suspended,
+                format_bytes(job.usage.rss),
+                ports
+            )
+        })
+        .collect();
+    for (job, line) in jobs.iter().zip(&lines) {
+        jobs_map.insert(job.number, line.clone());
+    }
 
-    let input = jobs_map
-        .clone()
-        .into_values()
-        .collect::<Vec<String>>()
-        .join("\n");
+    // Keep the order the jobs were given in, e.g. by `--sort`.
+    let input = lines.join("\n");
 
     (jobs_map, input)
 }
 
-pub fn run_fzf_cmd(input: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let mut child = Command::new("fzf")
+/// Quote `text` for use as a single word in a POSIX shell command.
+pub fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Let the user pick one line of `input` with fzf, running `preview` as
+/// fzf's preview command if given.
+pub fn run_fzf_cmd(
+    input: &str,
+    preview: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut command = Command::new("fzf");
+    if let Some(preview) = preview {
+        command.arg("--preview").arg(preview);
+    }
+
+    let mut child = command
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()?;
@@ -94,3 +665,145 @@ pub fn run_fzf_cmd(input: &str) -> Result<String, Box<dyn std::error::Error>> {
         Err("fzf selection cancelled or failed".into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_signals_by_number_and_name() {
+        assert_eq!(parse_signal("15"), Ok(libc::SIGTERM));
+        assert_eq!(parse_signal("0"), Ok(0));
+        assert_eq!(parse_signal("TERM"), Ok(libc::SIGTERM));
+        assert_eq!(parse_signal("sigterm"), Ok(libc::SIGTERM));
+        assert_eq!(parse_signal(" SIGKILL "), Ok(libc::SIGKILL));
+        assert_eq!(parse_signal("cont"), Ok(libc::SIGCONT));
+    }
+
+    #[test]
+    fn rejects_unknown_signals() {
+        assert!(parse_signal("65").is_err());
+        assert!(parse_signal("-1").is_err());
+        assert!(parse_signal("SIGFOO").is_err());
+        assert!(parse_signal("").is_err());
+    }
+
+    #[test]
+    fn recovers_poisoned_locks() {
+        let mutex = Mutex::new(vec![1]);
+        let _ = std::panic::catch_unwind(|| {
+            let mut guard = lock(&mutex);
+            guard.push(2);
+            panic!("handler failed");
+        });
+
+        assert!(mutex.is_poisoned());
+        assert_eq!(*lock(&mutex), [1, 2]);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration(" 2h "), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("1w"), Ok(Duration::from_secs(604800)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("1y").is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+        assert!(parse_duration(&format!("{}s", u64::MAX)).is_ok());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("512k"), Ok(512 << 10));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert_eq!(parse_size("2GB"), Ok(2 << 30));
+        assert_eq!(parse_size(" 2GiB "), Ok(2 << 30));
+        assert_eq!(parse_size("100B"), Ok(100));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1K").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("2P").is_err());
+        assert!(parse_size("2 G").is_err());
+        assert!(parse_size("16777216T").is_err());
+        assert_eq!(parse_size("16777215T"), Ok(16777215 << 40));
+    }
+
+    #[test]
+    fn parses_io_priorities() {
+        let priority = |class, level| Ok(IoPriority { class, level });
+        assert_eq!(parse_ionice("idle"), priority(IoClass::Idle, 4));
+        assert_eq!(parse_ionice("be"), priority(IoClass::BestEffort, 4));
+        assert_eq!(
+            parse_ionice("best-effort:0"),
+            priority(IoClass::BestEffort, 0)
+        );
+        assert_eq!(parse_ionice("RT:7"), priority(IoClass::Realtime, 7));
+        assert_eq!(parse_ionice("realtime:2"), priority(IoClass::Realtime, 2));
+    }
+
+    #[test]
+    fn rejects_invalid_io_priorities() {
+        assert!(parse_ionice("").is_err());
+        assert!(parse_ionice("fast").is_err());
+        assert!(parse_ionice("be:8").is_err());
+        assert!(parse_ionice("be:-1").is_err());
+        assert!(parse_ionice("be:").is_err());
+        assert!(parse_ionice(":4").is_err());
+    }
+
+    #[test]
+    #[allow(clippy::zombie_processes)] // Reaped by `try_reap` instead.
+    fn reaps_exited_children() {
+        let mut child = Command::new("sh")
+            .args(["-c", "read x; exit 3"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        assert!(child_processes().contains(&pid));
+        assert_eq!(try_reap(pid).unwrap(), None);
+
+        drop(child.stdin.take());
+        let status = loop {
+            if let Some(status) = try_reap(pid).unwrap() {
+                break status;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(status.code(), Some(3));
+        assert!(!child_processes().contains(&pid));
+        assert_eq!(
+            try_reap(pid).unwrap_err().raw_os_error(),
+            Some(libc::ECHILD)
+        );
+    }
+
+    #[test]
+    fn names_signals() {
+        assert_eq!(signal_name(libc::SIGTSTP), "SIGTSTP");
+        assert_eq!(signal_name(40), "40");
+        assert_eq!(parse_signal(&signal_name(libc::SIGHUP)), Ok(libc::SIGHUP));
+    }
+}
@@ -0,0 +1,174 @@
+use jobctl::sessions::{ClientRequest, SCHEMA_TYPES, ServerResponse, schema};
+use serde_json::{Value, json};
+
+fn validator(name: &str) -> jsonschema::Validator {
+    let schema = schema(name).unwrap().to_value();
+    jsonschema::validator_for(&schema).unwrap()
+}
+
+fn assert_valid(validator: &jsonschema::Validator, value: &Value) {
+    if let Err(e) = validator.validate(value) {
+        panic!("{} does not match the schema: {}", value, e);
+    }
+}
+
+#[test]
+fn every_type_has_a_schema() {
+    for name in SCHEMA_TYPES {
+        let schema = schema(name).unwrap().to_value();
+        assert_eq!(schema["title"], *name);
+        jsonschema::validator_for(&schema).unwrap();
+    }
+}
+
+#[test]
+fn requests_round_trip_through_schema() {
+    let validator = validator("ClientRequest");
+    let samples = [
+        json!({ "action": "list", "cwd": "/home/user" }),
+        json!({ "action": "list", "fzf": false, "dir": "/tmp", "cwd": "/" }),
+        json!({ "action": "register", "pid": 4242, "number": 1, "command": "vim", "cwd": "/src" }),
+        json!({ "action": "run", "command": "sleep 60", "cwd": "/src" }),
+        json!({
+            "action": "run", "command": "make -j8", "nice": 10,
+            "ionice": { "class": "best_effort", "level": 7 }, "max_mem": 2147483648u64,
+            "max_cpu_seconds": 3600, "max_files": 1024, "cwd": "/src"
+        }),
+        json!({ "action": "renice", "job": "make", "nice": -5, "cwd": "/src" }),
+        json!({ "action": "kill", "cwd": "/" }),
+        json!({ "action": "kill", "jobs": "term", "cwd": "/" }),
+        json!({ "action": "signal", "job": "%1", "signal": 15, "cwd": "/src" }),
+        json!({ "action": "freeze", "job": "vim", "cwd": "/src" }),
+        json!({ "action": "inspect", "job": "%1", "cwd": "/src" }),
+        json!({ "action": "inspect", "job": "%1", "tree": true, "cwd": "/src" }),
+        json!({ "action": "which_port", "port": 3000, "cwd": "/" }),
+        json!({ "action": "status", "cwd": "/" }),
+        json!({ "action": "metrics", "cwd": "/" }),
+        json!({ "action": "gc", "cwd": "/" }),
+        json!({ "action": "gc", "dry_run": true, "cwd": "/" }),
+        json!({ "action": "kill_session", "dir": "/src", "cwd": "/" }),
+        json!({ "action": "kill_session", "dir": "/src", "grace_ms": 1000, "cwd": "/" }),
+        json!({ "action": "upgrade", "binary": "/usr/bin/job-server", "cwd": "/" }),
+    ];
+
+    for sample in samples {
+        assert_valid(&validator, &sample);
+
+        let request: ClientRequest = serde_json::from_value(sample).unwrap();
+        let encoded = serde_json::to_value(&request).unwrap();
+        assert_valid(&validator, &encoded);
+
+        let decoded: ClientRequest = serde_json::from_value(encoded.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), encoded);
+    }
+}
+
+#[test]
+fn responses_round_trip_through_schema() {
+    let validator = validator("ServerResponse");
+    let job = json!({
+        "pid": 4242, "command": "vim", "number": 1, "suspended": 1700000000,
+        "state": "stopped", "start_time": 123456, "frozen": 0, "reclaimed": 0,
+        "history": [{ "state": "stopped", "at": 1700000000 }]
+    });
+    let usage = json!({
+        "rss": 52428800, "virtual_memory": 209715200, "cpu_time_ms": 1500,
+        "threads": 4, "children": 2
+    });
+    let socket = json!({
+        "protocol": "tcp", "address": "127.0.0.1:3000", "port": 3000, "state": "listen",
+        "pid": 4250
+    });
+    let details = json!({
+        "pgid": 4242, "sid": 4100, "shell_pid": 4100, "shell": "-zsh", "tty": "/dev/pts/3",
+        "cmdline": "vim src/main.rs", "exe": "/usr/bin/vim", "cwd": "/src",
+        "started": 1699999000, "usage": usage, "open_files": 12,
+        "sockets": [socket, {
+            "protocol": "unix", "address": "/tmp/app.sock", "port": null, "state": "listen",
+            "pid": 4250
+        }]
+    });
+    let inspection = |tree: Option<Value>| {
+        let mut inspection = details.clone();
+        inspection["directory"] = json!("/src");
+        inspection["job"] = job.clone();
+        if let Some(tree) = tree {
+            inspection["tree"] = tree;
+        }
+        inspection
+    };
+    let samples = [
+        json!({ "action": "list_jobs", "jobs": [
+            { "pid": 4242, "command": "vim", "number": 1, "suspended": "5m ago", "state": "running",
+              "usage": usage, "ports": [3000] }
+        ] }),
+        json!({ "action": "list_sessions", "sessions": [{ "jobs": [job], "directory": "/src" }] }),
+        json!({ "action": "list_sessions", "sessions": [
+            { "jobs": [job], "directory": "/src", "usage": usage }
+        ] }),
+        json!({ "action": "register", "job": job }),
+        json!({ "action": "kill" }),
+        json!({ "action": "upgrade", "pid": 1234 }),
+        json!({ "action": "signal", "job": job, "signal": 19 }),
+        json!({ "action": "freeze", "job": job, "reclaimed": 1048576 }),
+        json!({ "action": "renice", "job": job, "nice": 10, "threads": 3 }),
+        json!({ "action": "inspect", "inspection": inspection(None) }),
+        json!({ "action": "inspect", "inspection": inspection(Some(json!([
+            { "pid": 4242, "ppid": 100, "depth": 0, "state": "stopped", "command": "make",
+              "still_running": false },
+            { "pid": 4250, "ppid": 4242, "depth": 1, "state": "sleeping", "command": "npm run dev",
+              "still_running": true }
+        ]))) }),
+        json!({ "action": "gc", "jobs": [
+            { "directory": "/src", "job": job, "action": "freeze", "suspended_for": 604800 }
+        ] }),
+        json!({ "action": "which_port", "port": 3000, "owners": [
+            { "socket": socket, "command": "node server.js", "directory": "/src", "job": job },
+            { "socket": socket, "command": "python3 -m http.server", "directory": null, "job": null }
+        ] }),
+        json!({ "action": "kill_session", "directory": "/src", "jobs": [
+            { "job": job, "outcome": "terminated" },
+            { "job": job, "outcome": "failed", "error": "Operation not permitted" }
+        ] }),
+        json!({ "action": "status", "status": {
+            "version": "0.1.0", "pid": 1234, "socket_path": "/tmp/jobctl-1000.sock",
+            "started": 1700000000, "uptime": 60, "sessions": 1, "jobs": 1,
+            "connections": 1, "subscribers": 0, "last_error": null
+        } }),
+        json!({ "action": "metrics", "text": "jobctl_sessions 0\n" }),
+        json!({ "action": "error", "code": "not_found", "message": "No session for /src" }),
+    ];
+
+    for sample in samples {
+        assert_valid(&validator, &sample);
+
+        let response: ServerResponse = serde_json::from_value(sample.clone()).unwrap();
+        let encoded = serde_json::to_value(&response).unwrap();
+        assert_valid(&validator, &encoded);
+        assert_eq!(encoded, sample);
+    }
+}
+
+#[test]
+fn schema_rejects_malformed_requests() {
+    let validator = validator("ClientRequest");
+    let samples = [
+        json!({ "action": "list" }),
+        json!({ "action": "launch", "cwd": "/" }),
+        json!({ "action": "run", "cwd": "/" }),
+        json!({ "action": "register", "pid": -1, "number": 1, "command": "vim", "cwd": "/" }),
+        json!({ "action": "kill", "jobs": "stop", "cwd": "/" }),
+        json!({ "action": "signal", "job": "%1", "signal": "TERM", "cwd": "/" }),
+        json!({ "action": "which_port", "port": 70000, "cwd": "/" }),
+        json!({ "action": "run", "command": "make", "ionice": { "class": "fast" }, "cwd": "/" }),
+    ];
+
+    for sample in samples {
+        assert!(
+            !validator.is_valid(&sample),
+            "{} should be rejected",
+            sample
+        );
+        assert!(serde_json::from_value::<ClientRequest>(sample).is_err());
+    }
+}